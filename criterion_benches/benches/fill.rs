@@ -9,7 +9,9 @@ extern crate memorydb;
 extern crate parity_bytes;
 extern crate patricia_trie as trie;
 extern crate patricia_trie_ethereum as ethtrie;
+extern crate rlp;
 extern crate trie_standardmap;
+extern crate triehash;
 
 use criterion::{Bencher, Criterion, Fun};
 
@@ -21,6 +23,7 @@ use parity_bytes::Bytes;
 use trie::{Trie, TrieMut};
 use trie_standardmap::{Alphabet, StandardMap, ValueMode};
 
+use quick_patricia_trie::ordered::ordered_trie_root;
 use quick_patricia_trie::trie::Trie as QuickTrie;
 
 fn random_word(alphabet: &[u8], min_count: usize, diff_count: usize, seed: &mut H256) -> Vec<u8> {
@@ -62,7 +65,7 @@ fn trie_insertion_32_mir_1k(c: &mut Criterion) {
     let parity = Fun::new("parity", |b: &mut Bencher, d: &Vec<(Bytes, Bytes)>| {
         b.iter(|| {
             let mut memdb = MemoryDB::<KeccakHasher>::new();
-            let mut root = H256::new();
+            let mut root = H256::zero();
             let mut t = TrieDBMut::new(&mut memdb, &mut root);
             for i in d.iter() {
                 t.insert(&i.0, &i.1).unwrap();
@@ -74,7 +77,7 @@ fn trie_insertion_32_mir_1k(c: &mut Criterion) {
         b.iter(|| {
             let mut t = QuickTrie::new();
             for i in d.iter() {
-                t.insert(&i.0, &i.1);
+                t.insert(&i.0, &i.1).unwrap();
             }
         })
     });
@@ -96,7 +99,7 @@ fn trie_insertion_32_ran_1k(c: &mut Criterion) {
     let parity = Fun::new("parity", |b: &mut Bencher, d: &Vec<(Bytes, Bytes)>| {
         b.iter(|| {
             let mut memdb = MemoryDB::<KeccakHasher>::new();
-            let mut root = H256::new();
+            let mut root = H256::zero();
             let mut t = TrieDBMut::new(&mut memdb, &mut root);
             for i in d.iter() {
                 t.insert(&i.0, &i.1).unwrap();
@@ -108,7 +111,7 @@ fn trie_insertion_32_ran_1k(c: &mut Criterion) {
         b.iter(|| {
             let mut t = QuickTrie::new();
             for i in d.iter() {
-                t.insert(&i.0, &i.1);
+                t.insert(&i.0, &i.1).unwrap();
             }
         })
     });
@@ -119,7 +122,7 @@ fn trie_insertion_32_ran_1k(c: &mut Criterion) {
 
 fn trie_insertion_six_high(c: &mut Criterion) {
     let mut d: Vec<(Bytes, Bytes)> = Vec::new();
-    let mut seed = H256::new();
+    let mut seed = H256::zero();
     for _ in 0..1000 {
         let k = random_bytes(6, 0, &mut seed);
         let v = random_value(&mut seed);
@@ -129,7 +132,7 @@ fn trie_insertion_six_high(c: &mut Criterion) {
     let parity = Fun::new("parity", |b: &mut Bencher, d: &Vec<(Bytes, Bytes)>| {
         b.iter(|| {
             let mut memdb = MemoryDB::<KeccakHasher>::new();
-            let mut root = H256::new();
+            let mut root = H256::zero();
             let mut t = TrieDBMut::new(&mut memdb, &mut root);
             for i in d.iter() {
                 t.insert(&i.0, &i.1).unwrap();
@@ -141,7 +144,7 @@ fn trie_insertion_six_high(c: &mut Criterion) {
         b.iter(|| {
             let mut t = QuickTrie::new();
             for i in d.iter() {
-                t.insert(&i.0, &i.1);
+                t.insert(&i.0, &i.1).unwrap();
             }
         })
     });
@@ -153,7 +156,7 @@ fn trie_insertion_six_high(c: &mut Criterion) {
 fn trie_insertion_six_mid(c: &mut Criterion) {
     let alphabet = b"@QWERTYUIOPASDFGHJKLZXCVBNM[/]^_";
     let mut d: Vec<(Bytes, Bytes)> = Vec::new();
-    let mut seed = H256::new();
+    let mut seed = H256::zero();
     for _ in 0..1000 {
         let k = random_word(alphabet, 6, 0, &mut seed);
         let v = random_value(&mut seed);
@@ -163,7 +166,7 @@ fn trie_insertion_six_mid(c: &mut Criterion) {
     let parity = Fun::new("parity", |b: &mut Bencher, d: &Vec<(Bytes, Bytes)>| {
         b.iter(|| {
             let mut memdb = MemoryDB::<KeccakHasher>::new();
-            let mut root = H256::new();
+            let mut root = H256::zero();
             let mut t = TrieDBMut::new(&mut memdb, &mut root);
             for i in d.iter() {
                 t.insert(&i.0, &i.1).unwrap();
@@ -175,7 +178,7 @@ fn trie_insertion_six_mid(c: &mut Criterion) {
         b.iter(|| {
             let mut t = QuickTrie::new();
             for i in d.iter() {
-                t.insert(&i.0, &i.1);
+                t.insert(&i.0, &i.1).unwrap();
             }
         })
     });
@@ -187,7 +190,7 @@ fn trie_insertion_six_mid(c: &mut Criterion) {
 fn trie_insertion_random_mid(c: &mut Criterion) {
     let alphabet = b"@QWERTYUIOPASDFGHJKLZXCVBNM[/]^_";
     let mut d: Vec<(Bytes, Bytes)> = Vec::new();
-    let mut seed = H256::new();
+    let mut seed = H256::zero();
     for _ in 0..1000 {
         let k = random_word(alphabet, 1, 5, &mut seed);
         let v = random_value(&mut seed);
@@ -197,7 +200,7 @@ fn trie_insertion_random_mid(c: &mut Criterion) {
     let parity = Fun::new("parity", |b: &mut Bencher, d: &Vec<(Bytes, Bytes)>| {
         b.iter(|| {
             let mut memdb = MemoryDB::<KeccakHasher>::new();
-            let mut root = H256::new();
+            let mut root = H256::zero();
             let mut t = TrieDBMut::new(&mut memdb, &mut root);
             for i in d.iter() {
                 t.insert(&i.0, &i.1).unwrap();
@@ -209,7 +212,7 @@ fn trie_insertion_random_mid(c: &mut Criterion) {
         b.iter(|| {
             let mut t = QuickTrie::new();
             for i in d.iter() {
-                t.insert(&i.0, &i.1);
+                t.insert(&i.0, &i.1).unwrap();
             }
         })
     });
@@ -229,7 +232,7 @@ fn trie_iter(c: &mut Criterion) {
     let d = st.make();
 
     let mut memdb = MemoryDB::<KeccakHasher>::new();
-    let mut root = H256::new();
+    let mut root = H256::zero();
     {
         let mut t = TrieDBMut::new(&mut memdb, &mut root);
         for i in d.iter() {
@@ -247,9 +250,9 @@ fn trie_iter(c: &mut Criterion) {
 
     let mut t = QuickTrie::new();
     for i in d.iter() {
-        t.insert(&i.0, &i.1);
+        t.insert(&i.0, &i.1).unwrap();
     }
-    t.commit();
+    t.commit().unwrap();
     let quick = Fun::new("quick", move |b: &mut Bencher, _d: &()| {
         b.iter(|| {
             for n in t.iter() {
@@ -262,6 +265,24 @@ fn trie_iter(c: &mut Criterion) {
     c.bench_functions("iter", functions, ());
 }
 
+fn ordered_root(c: &mut Criterion) {
+    let mut seed = H256::zero();
+    let d = (0..500)
+        .map(|_| random_bytes(1, 31, &mut seed))
+        .collect::<Vec<_>>();
+
+    let parity = Fun::new("parity", |b: &mut Bencher, d: &Vec<Vec<u8>>| {
+        b.iter(|| triehash::ordered_trie_root::<KeccakHasher, _, _>(d))
+    });
+
+    let quick = Fun::new("quick", |b: &mut Bencher, d: &Vec<Vec<u8>>| {
+        b.iter(|| ordered_trie_root(d))
+    });
+
+    let functions = vec![parity, quick];
+    c.bench_functions("ordered_root", functions, d);
+}
+
 criterion_group!(
     benches,
     trie_insertion_32_mir_1k,
@@ -269,6 +290,7 @@ criterion_group!(
     trie_insertion_six_high,
     trie_insertion_six_mid,
     trie_insertion_random_mid,
-    trie_iter
+    trie_iter,
+    ordered_root
 );
 criterion_main!(benches);
@@ -1,8 +1,20 @@
+use std::mem;
+use std::sync::Arc;
+
+/// Number of stacked bases above which they are flattened into one
+pub const MAX_DEPTH: usize = 8;
+
 /// A struct to hold all bytes into the same Vec
+///
+/// An arena may be stacked on top of a shared, read-only `base`: indices up to
+/// `offset` refer to the base items, new items are pushed after them.
 #[derive(Debug)]
 pub struct Arena {
     data: Vec<u8>,
     pos: Vec<usize>,
+    base: Option<Arc<Arena>>,
+    /// Number of items in `base`
+    offset: usize,
 }
 
 impl Arena {
@@ -10,15 +22,83 @@ impl Arena {
         Arena {
             data: Vec::new(),
             pos: vec![0],
+            base: None,
+            offset: 0,
         }
     }
 
+    /// Creates an empty arena on top of `base`, whose items are shared and never modified
+    pub fn with_base(base: Arc<Arena>) -> Arena {
+        Arena {
+            data: Vec::new(),
+            pos: vec![0],
+            offset: base.len(),
+            base: Some(base),
+        }
+    }
+
+    /// Creates another empty arena on top of the same base
+    pub fn fork(&self) -> Arena {
+        Arena {
+            data: Vec::new(),
+            pos: vec![0],
+            base: self.base.clone(),
+            offset: self.offset,
+        }
+    }
+
+    /// Moves all items into a shared base, this arena being left on top of it
+    ///
+    /// Items are appended to the current base if nothing else shares it. Otherwise a new
+    /// base is stacked, the bases being flattened once there are `MAX_DEPTH` of them.
+    pub fn share(&mut self) {
+        if let Some(base) = self.base.as_mut().and_then(Arc::get_mut) {
+            for i in 1..self.pos.len() {
+                base.push(&self.data[self.pos[i - 1]..self.pos[i]]);
+            }
+            self.offset = base.len();
+            self.clear();
+            return;
+        }
+        let mut base = mem::take(self);
+        if base.depth() >= MAX_DEPTH {
+            base.flatten();
+        }
+        *self = Arena::with_base(Arc::new(base));
+    }
+
+    /// Number of stacked bases
+    pub fn depth(&self) -> usize {
+        self.base.as_ref().map_or(0, |base| 1 + base.depth())
+    }
+
+    /// Copies all the items, shared ones included, into a single arena
+    fn flatten(&mut self) {
+        let mut flat = Arena::new();
+        for i in 1..=self.len() {
+            flat.push(&self[i]);
+        }
+        *self = flat;
+    }
+
+    /// Number of items, including the shared ones
+    pub fn len(&self) -> usize {
+        self.offset + self.pos.len() - 1
+    }
+
+    /// Returns true if there is no item at all
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     pub fn with_capacity(data_cap: usize, item_cap: usize) -> Arena {
         let mut pos = Vec::with_capacity(item_cap + 1);
         pos.push(0);
         Arena {
             data: Vec::with_capacity(data_cap),
             pos,
+            base: None,
+            offset: 0,
         }
     }
 
@@ -31,7 +111,18 @@ impl Arena {
         );
         self.data.extend_from_slice(data);
         self.pos.push(self.data.len());
-        self.pos.len() - 1
+        self.len()
+    }
+
+    /// Total size of the data, in bytes, shared data excluded
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Removes all owned data, keeping the allocated memory
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.pos.truncate(1);
     }
 
     pub fn insert(&mut self, index: usize, data: &[u8]) {
@@ -42,13 +133,25 @@ impl Arena {
             index,
             self.data.len()
         );
+        assert!(index > self.offset, "cannot overwrite shared data");
+        let index = index - self.offset;
         self.data[self.pos[index - 1]..self.pos[index]].copy_from_slice(data);
     }
 }
 
+impl Default for Arena {
+    fn default() -> Arena {
+        Arena::new()
+    }
+}
+
 impl ::std::ops::Index<usize> for Arena {
     type Output = [u8];
     fn index(&self, i: usize) -> &[u8] {
+        if i <= self.offset {
+            return &self.base.as_ref().expect("shared item")[i];
+        }
+        let i = i - self.offset;
         &self.data[self.pos[i - 1]..self.pos[i]]
     }
 }
@@ -64,7 +167,8 @@ impl<'a> ::std::ops::Index<usize> for ArenaSlice<'a> {
 
 #[cfg(test)]
 mod test {
-    use super::Arena;
+    use super::{Arena, MAX_DEPTH};
+    use std::sync::Arc;
 
     #[test]
     fn arena() {
@@ -74,4 +178,52 @@ mod test {
         assert_eq!(&arena[idx], "test".as_bytes());
         assert_eq!(&arena[idx2], "test2".as_bytes(), "{:?}", arena);
     }
+
+    #[test]
+    fn shared() {
+        let mut arena = Arena::new();
+        let idx = arena.push("test".as_bytes());
+        arena.share();
+        let mut fork = arena.fork();
+        let idx2 = arena.push("test2".as_bytes());
+        let idx3 = fork.push("test3".as_bytes());
+        assert_eq!(idx2, idx3);
+        assert_eq!(&fork[idx], "test".as_bytes());
+        assert_eq!(&arena[idx2], "test2".as_bytes());
+        assert_eq!(&fork[idx3], "test3".as_bytes());
+        assert_eq!(arena.size(), 5);
+
+        let mut stacked = Arena::with_base(Arc::new(arena));
+        let idx4 = stacked.push("test4".as_bytes());
+        assert_eq!(&stacked[idx], "test".as_bytes());
+        assert_eq!(&stacked[idx2], "test2".as_bytes());
+        assert_eq!(&stacked[idx4], "test4".as_bytes());
+        assert_eq!(stacked.len(), 3);
+    }
+
+    #[test]
+    fn share_depth() {
+        // without forks, items are appended to the unique base
+        let mut arena = Arena::new();
+        for i in 0..20u8 {
+            arena.push(&[i]);
+            arena.share();
+        }
+        assert_eq!(arena.depth(), 1);
+
+        // bases kept alive by forks are flattened
+        let mut forks = Vec::new();
+        for i in 0..20u8 {
+            arena.push(&[i, i]);
+            arena.share();
+            forks.push(arena.fork());
+            assert!(arena.depth() <= MAX_DEPTH);
+        }
+        assert_eq!(arena.len(), 40);
+        for i in 0..20u8 {
+            assert_eq!(&arena[i as usize + 1], &[i][..]);
+            assert_eq!(&arena[i as usize + 21], &[i, i][..]);
+            assert_eq!(&forks[19][i as usize + 21], &[i, i][..]);
+        }
+    }
 }
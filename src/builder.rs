@@ -0,0 +1,325 @@
+//! Streaming root computation out of items sorted by key
+//!
+//! Unlike `Trie`, `StackTrieBuilder` never holds the whole trie: only the branches on
+//! the path of the last inserted key are kept, every other subtrie is hashed (and
+//! written into the store) as soon as no later key can reach it.
+
+use arena::Arena;
+use db::Index;
+use error::TrieError;
+use hasher::{Hasher, Keccak};
+use nibbles::Nibble;
+use node::{Branch, Extension, Leaf};
+use std::marker::PhantomData;
+use store::NodeStore;
+
+/// A branch on the path of the last inserted key, which may still get new children
+#[derive(Debug)]
+struct Frame {
+    /// Nibble position of the branch, children are indexed by the key nibble at `depth`
+    depth: usize,
+    /// Children references, either hashes or inlined encodings
+    children: [Option<Vec<u8>>; 16],
+    value: Option<Vec<u8>>,
+}
+
+impl Frame {
+    fn new(depth: usize) -> Self {
+        Frame {
+            depth,
+            children: Default::default(),
+            value: None,
+        }
+    }
+}
+
+/// A finished subtrie not yet attached to its parent
+#[derive(Debug)]
+enum Pending {
+    /// The value of the last inserted key
+    Leaf(Vec<u8>),
+    /// The reference of a branch and its nibble position
+    Branch(Vec<u8>, usize),
+}
+
+/// Computes a trie root out of (key, value) pairs inserted in ascending key order
+///
+/// Memory is bounded by the trie depth. Hashed nodes are written into the store, if any,
+/// so the resulting trie can be opened with `Trie::from_root`.
+#[derive(Debug)]
+pub struct StackTrieBuilder<S: NodeStore = (), H: Hasher = Keccak> {
+    /// Scratch space used to encode one node at a time
+    arena: Arena,
+    stack: Vec<Frame>,
+    /// Nibbles and value of the last inserted key
+    last: Option<(Vec<u8>, Vec<u8>)>,
+    store: S,
+    hasher: PhantomData<H>,
+}
+
+impl StackTrieBuilder {
+    /// Creates a new `StackTrieBuilder`
+    pub fn new() -> Self {
+        StackTrieBuilder::with_store(())
+    }
+}
+
+impl Default for StackTrieBuilder {
+    fn default() -> Self {
+        StackTrieBuilder::new()
+    }
+}
+
+impl<S: NodeStore> StackTrieBuilder<S> {
+    /// Creates a new `StackTrieBuilder` writing hashed nodes into a store
+    pub fn with_store(store: S) -> Self {
+        StackTrieBuilder::with_hasher(store)
+    }
+}
+
+impl<S: NodeStore, H: Hasher> StackTrieBuilder<S, H> {
+    /// Creates a new `StackTrieBuilder` writing into a store and hashing nodes with `H`
+    pub fn with_hasher(store: S) -> Self {
+        StackTrieBuilder {
+            arena: Arena::new(),
+            stack: Vec::new(),
+            last: None,
+            store,
+            hasher: PhantomData,
+        }
+    }
+
+    /// Insert a (key, value)
+    ///
+    /// Fails with `TrieError::UnorderedKey` if `key` is not greater than the previous key
+    pub fn insert<K, V>(&mut self, key: K, value: V) -> Result<(), TrieError>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let key = key.as_ref();
+        let nibbles = key
+            .iter()
+            .flat_map(|b| Some(b >> 4).into_iter().chain(Some(b & 0x0F)))
+            .collect::<Vec<_>>();
+
+        if let Some((ref last, _)) = self.last {
+            if nibbles <= *last {
+                return Err(TrieError::UnorderedKey(key.to_vec()));
+            }
+        }
+        let (last, last_value) = match self.last.replace((nibbles, value.as_ref().to_vec())) {
+            Some(last) => last,
+            None => return Ok(()),
+        };
+
+        // the last key diverges from the new one at `common`, where a branch is needed:
+        // deeper branches cannot change anymore
+        let common = {
+            let (next, _) = self.last.as_ref().expect("just inserted");
+            last.iter().zip(next).take_while(|(a, b)| a == b).count()
+        };
+        let pending = self.close(&last, Pending::Leaf(last_value), common + 1)?;
+        let mut frame = match self.stack.pop() {
+            Some(frame) if frame.depth == common => frame,
+            Some(frame) => {
+                self.stack.push(frame);
+                Frame::new(common)
+            }
+            None => Frame::new(common),
+        };
+        self.attach(&mut frame, &last, pending)?;
+        self.stack.push(frame);
+        Ok(())
+    }
+
+    /// Hash all remaining nodes and returns the trie root
+    pub fn finish(mut self) -> Result<Vec<u8>, TrieError> {
+        let (last, last_value) = match self.last.take() {
+            Some(last) => last,
+            None => return Ok(H::null_node().as_ref().to_vec()),
+        };
+        let pending = self.close(&last, Pending::Leaf(last_value), 0)?;
+        self.reference(&last, 0, pending, true)
+    }
+
+    /// Hash all branches at or deeper than `depth`, from the deepest one
+    ///
+    /// Returns the resulting subtrie, to be attached at `key`
+    fn close(
+        &mut self,
+        key: &[u8],
+        mut pending: Pending,
+        depth: usize,
+    ) -> Result<Pending, TrieError> {
+        while self.stack.last().map_or(false, |f| f.depth >= depth) {
+            let mut frame = self.stack.pop().expect("checked above");
+            self.attach(&mut frame, key, pending)?;
+            let frame_depth = frame.depth;
+            pending = Pending::Branch(self.encode_branch(frame)?, frame_depth);
+        }
+        Ok(pending)
+    }
+
+    /// Set a subtrie on `key` path as a child (or the value) of `frame`
+    fn attach(&mut self, frame: &mut Frame, key: &[u8], pending: Pending) -> Result<(), TrieError> {
+        match pending {
+            Pending::Leaf(value) if key.len() == frame.depth => frame.value = Some(value),
+            pending => {
+                let child = self.reference(key, frame.depth + 1, pending, false)?;
+                frame.children[key[frame.depth] as usize] = Some(child);
+            }
+        }
+        Ok(())
+    }
+
+    /// Encode a subtrie whose path starts at nibble `from` of `key`
+    ///
+    /// Returns either its hash or its encoding, if short enough to be inlined
+    fn reference(
+        &mut self,
+        key: &[u8],
+        from: usize,
+        pending: Pending,
+        is_root: bool,
+    ) -> Result<Vec<u8>, TrieError> {
+        self.arena.clear();
+        let encoded = match pending {
+            Pending::Leaf(value) => {
+                let nibble = Nibble::from_nibbles(&key[from..], &mut self.arena);
+                let value = self.arena.push(&value);
+                Leaf { nibble, value }.encoded(&mut self.arena)
+            }
+            Pending::Branch(child, depth) if depth == from => {
+                if !is_root || child.len() >= H::LENGTH {
+                    return Ok(child);
+                }
+                // an inlined root must be hashed anyway
+                self.arena.push(&child)
+            }
+            Pending::Branch(child, depth) => {
+                let nibble = Nibble::from_nibbles(&key[from..depth], &mut self.arena);
+                let key = Index::Hash(self.arena.push(&child));
+                Extension { nibble, key }
+                    .encoded::<H>(&mut self.arena)
+                    .expect("extension child is hashed")
+            }
+        };
+        self.hashed(encoded, is_root)
+    }
+
+    fn encode_branch(&mut self, frame: Frame) -> Result<Vec<u8>, TrieError> {
+        self.arena.clear();
+        let mut branch = Branch::default();
+        for (key, child) in branch.keys.iter_mut().zip(&frame.children) {
+            if let Some(child) = child {
+                *key = Some(Index::Hash(self.arena.push(child)));
+            }
+        }
+        if let Some(value) = frame.value {
+            branch.value = Some(self.arena.push(&value));
+        }
+        let encoded = branch.encoded::<H>(&mut self.arena);
+        self.hashed(encoded, false)
+    }
+
+    /// Hash the encoded node (and write it into the store) unless it can be inlined
+    fn hashed(&mut self, encoded: usize, is_root: bool) -> Result<Vec<u8>, TrieError> {
+        let data = &self.arena[encoded];
+        if !is_root && data.len() < H::LENGTH {
+            return Ok(data.to_vec());
+        }
+        let hash = H::hash(data);
+        self.store.put(hash.as_ref(), data)?;
+        Ok(hash.as_ref().to_vec())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use keccak_hash::KECCAK_NULL_RLP;
+    use keccak_hasher::KeccakHasher;
+    use std::collections::HashMap;
+    use testutil::mixed_sample;
+    use trie::Trie;
+    use triehash::trie_root;
+
+    fn root(items: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+        let mut builder = StackTrieBuilder::new();
+        for (k, v) in items {
+            builder.insert(k, v).unwrap();
+        }
+        builder.finish().unwrap()
+    }
+
+    #[test]
+    fn stack_root() {
+        assert_eq!(root(&[]), &*KECCAK_NULL_RLP);
+        for n in &[1, 2, 3, 10, 50, 200, 1000] {
+            let items = mixed_sample(*n);
+            assert_eq!(
+                root(&items),
+                &*trie_root::<KeccakHasher, _, _, _>(items.clone()),
+                "{} items",
+                n
+            );
+        }
+
+        // tiny nodes are inlined
+        let items = vec![(vec![0x01], vec![0x01]), (vec![0x01, 0x02], vec![0x02])];
+        assert_eq!(
+            root(&items),
+            &*trie_root::<KeccakHasher, _, _, _>(items.clone())
+        );
+    }
+
+    #[test]
+    fn stack_store() {
+        let items = mixed_sample(300);
+        let mut built = HashMap::new();
+        let mut builder = StackTrieBuilder::with_store(&mut built);
+        for (k, v) in &items {
+            builder.insert(k, v).unwrap();
+        }
+        let root = builder.finish().unwrap();
+
+        let mut committed = HashMap::new();
+        {
+            let mut t = Trie::with_store(&mut committed);
+            for (k, v) in &items {
+                t.insert(k, v).unwrap();
+            }
+            assert_eq!(t.root().unwrap(), &*root);
+        }
+        assert_eq!(built, committed);
+
+        let mut t = Trie::from_root(&root, &mut built);
+        for (k, v) in &items {
+            assert_eq!(t.get_loading(k).unwrap(), Some(&**v));
+        }
+    }
+
+    #[test]
+    fn unordered() {
+        let mut builder = StackTrieBuilder::new();
+        builder.insert([0x01, 0x02], [0x01]).unwrap();
+        assert_eq!(
+            builder.insert([0x01, 0x02], [0x02]),
+            Err(TrieError::UnorderedKey(vec![0x01, 0x02]))
+        );
+        assert_eq!(
+            builder.insert([0x01], [0x02]),
+            Err(TrieError::UnorderedKey(vec![0x01]))
+        );
+        builder.insert([0x01, 0x03], [0x03]).unwrap();
+        let items = vec![
+            (vec![0x01, 0x02], vec![0x01]),
+            (vec![0x01, 0x03], vec![0x03]),
+        ];
+        assert_eq!(
+            builder.finish().unwrap(),
+            &*trie_root::<KeccakHasher, _, _, _>(items)
+        );
+    }
+}
@@ -1,8 +1,11 @@
-use arena::Arena;
-use keccak_hash::{keccak, H256, KECCAK_NULL_RLP};
-use node::Node;
+use arena::{Arena, MAX_DEPTH};
+use error::TrieError;
+use hasher::Hasher;
+use node::{Branch, Extension, Leaf, Node};
 use std::collections::HashMap;
 use std::mem;
+use std::sync::Arc;
+use store::NodeStore;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Index {
@@ -10,22 +13,78 @@ pub enum Index {
     Memory(usize),
 }
 
+/// Which nodes to evict when rebuilding a `Db`
+#[derive(Debug, Clone, Copy)]
+struct Eviction {
+    /// Nodes at this depth or deeper are evicted
+    depth: usize,
+    /// Only hashed nodes are in the store, inlined ones are shorter
+    hash_len: usize,
+}
+
+impl Eviction {
+    fn evicts(&self, depth: usize, key: &[u8]) -> bool {
+        depth >= self.depth && key.len() == self.hash_len
+    }
+}
+
+/// The state of a `Db` when a checkpoint was taken
+///
+/// Nodes are never modified in place while a checkpoint may refer to them: memory nodes
+/// below `memory` are copied on write and hashed nodes are kept.
+#[derive(Debug, Clone, Copy)]
+struct Checkpoint {
+    root: Index,
+    memory: usize,
+    available_hash_slots: usize,
+    dereferenced: usize,
+}
+
+/// The state of a `Db` before a commit, to revert it if its nodes can't be stored
+#[derive(Debug)]
+pub struct Uncommitted {
+    root: Index,
+    memory: Vec<Node>,
+    available_hash_slots: Vec<usize>,
+    dereferenced: Vec<usize>,
+    released: usize,
+    /// Hash slots the commit set, with the nodes they held
+    replaced: Vec<(usize, Option<Node>)>,
+    /// Recycled hash slots, with the hashes they held
+    overwritten: Vec<(usize, Vec<u8>)>,
+}
+
 /// A Merkle Storage
 ///
 /// Nodes are either stored in a simple Vec memory
-/// or pushed into a *database* with key = sha3(rlp(value))
+/// or pushed into a *database* with key = hash(rlp(value))
+///
+/// All nodes are hashed with the `Hasher` given to `Db::new`
+///
+/// Hashed nodes may also be found in a shared, read-only `base`, whose hash slots are the
+/// first `shared` ones of the arena. Such nodes are copied, never moved, into memory.
 #[derive(Debug)]
 pub struct Db {
     hash: HashMap<usize, Node>,
     memory: Vec<Node>,
     available_hash_slots: Vec<usize>,
+    /// Hash slots not referenced anymore since last commit
+    dereferenced: Vec<usize>,
+    checkpoints: Vec<Checkpoint>,
     empty: usize,
     root: Index,
+    base: Option<Arc<Db>>,
+    shared: usize,
+    /// Shared hash slots not referenced anymore since they were shared
+    released: Vec<usize>,
+    /// For forks, the number of commits of each stored node: other nodes belong to the
+    /// parent and are never dereferenced. `None` if all nodes are owned.
+    owned: Option<HashMap<Vec<u8>, usize>>,
 }
 
 impl Db {
-    pub fn new(arena: &mut Arena) -> Self {
-        let idx = arena.push(KECCAK_NULL_RLP.as_ref());
+    pub fn new<H: Hasher>(arena: &mut Arena) -> Self {
+        let idx = arena.push(H::null_node().as_ref());
         let mut hash = HashMap::new();
         hash.insert(idx, Node::Empty);
         Db {
@@ -33,7 +92,107 @@ impl Db {
             memory: Vec::new(),
             root: Index::Hash(idx),
             available_hash_slots: Vec::new(),
+            dereferenced: Vec::new(),
+            checkpoints: Vec::new(),
             empty: idx,
+            base: None,
+            shared: 0,
+            released: Vec::new(),
+            owned: None,
+        }
+    }
+
+    /// Creates an empty `Db` on top of `base`, with the same root
+    fn with_base(base: Arc<Db>, shared: usize) -> Self {
+        Db {
+            hash: HashMap::new(),
+            memory: Vec::new(),
+            available_hash_slots: Vec::new(),
+            dereferenced: Vec::new(),
+            checkpoints: Vec::new(),
+            empty: base.empty,
+            root: base.root,
+            base: Some(base),
+            shared,
+            released: Vec::new(),
+            owned: None,
+        }
+    }
+
+    /// Moves all hashed nodes into a shared base, along with the arena
+    ///
+    /// Must be called right after `commit`. Nothing is done if no node was added since
+    /// the last call. Nodes are merged into the current base if nothing else shares it,
+    /// otherwise a new base is stacked and the bases are flattened once there are
+    /// `MAX_DEPTH` of them.
+    pub fn share(&mut self, arena: &mut Arena) {
+        if self.base.is_some() && self.hash.is_empty() && arena.len() == self.shared {
+            return;
+        }
+        debug_assert!(self.memory.is_empty(), "sharing uncommitted nodes");
+        arena.share();
+        // the slots of the base cannot be written anymore
+        self.available_hash_slots.clear();
+        if let Some(base) = self.base.as_mut().and_then(Arc::get_mut) {
+            for hash in self.released.drain(..) {
+                base.hash.remove(&hash);
+            }
+            base.hash.extend(self.hash.drain());
+            self.shared = arena.len();
+            return;
+        }
+        let mut base = mem::replace(self, Db::empty());
+        base.released.clear();
+        if base.depth() >= MAX_DEPTH {
+            base.flatten();
+        }
+        let owned = base.owned.take();
+        *self = Db::with_base(Arc::new(base), arena.len());
+        self.owned = owned;
+    }
+
+    /// Number of stacked bases
+    pub fn depth(&self) -> usize {
+        self.base.as_ref().map_or(0, |base| 1 + base.depth())
+    }
+
+    /// Copies all the shared nodes, so that there is no base anymore
+    fn flatten(&mut self) {
+        let mut base = self.base.take();
+        while let Some(db) = base {
+            for (hash, node) in &db.hash {
+                self.hash.entry(*hash).or_insert_with(|| node.clone());
+            }
+            base = db.base.clone();
+        }
+        self.shared = 0;
+    }
+
+    /// Creates another `Db` on top of the same base
+    ///
+    /// Must be called right after `share`
+    pub fn fork(&self) -> Self {
+        let base = self.base.clone().expect("shared db");
+        let mut db = Db::with_base(base, self.shared);
+        db.root = self.root;
+        db.owned = Some(HashMap::new());
+        db
+    }
+
+    /// A placeholder `Db`, without any node
+    fn empty() -> Self {
+        Db {
+            hash: HashMap::new(),
+            memory: Vec::new(),
+            available_hash_slots: Vec::new(),
+            dereferenced: Vec::new(),
+            checkpoints: Vec::new(),
+            empty: 0,
+            root: Index::Hash(0),
+            base: None,
+            shared: 0,
+            released: Vec::new(),
+            owned: None,
         }
     }
 
@@ -48,26 +207,111 @@ impl Db {
         }
     }
 
+    pub(crate) fn set_root(&mut self, root: Index) {
+        self.root = root;
+    }
+
+    /// Checks if the node with this hash is loaded
+    pub fn contains(&self, hash: usize) -> bool {
+        self.get_hashed(hash).is_some()
+    }
+
+    /// Get a hashed node, either owned or shared
+    fn get_hashed(&self, hash: usize) -> Option<&Node> {
+        match self.hash.get(&hash) {
+            Some(node) => Some(node),
+            None => self.base.as_ref()?.get_hashed(hash),
+        }
+    }
+
+    /// Take a hashed node, shared ones are cloned
+    fn take_hashed(&mut self, hash: usize) -> Option<Node> {
+        match self.hash.remove(&hash) {
+            Some(node) => Some(node),
+            None => self.base.as_ref()?.get_hashed(hash).cloned(),
+        }
+    }
+
+    /// Load the node with this hash from the store
+    pub fn load<H: Hasher, S: NodeStore>(
+        &mut self,
+        hash: usize,
+        store: &S,
+        arena: &mut Arena,
+    ) -> Result<(), TrieError> {
+        debug!("loading node {}", hash);
+        match store.get(&arena[hash])? {
+            Some(encoded) => self.decode::<H>(hash, &encoded, arena),
+            None => Err(TrieError::MissingNode(arena[hash].to_vec())),
+        }
+    }
+
+    /// Decode a node and insert it, along with its inlined children
+    fn decode<H: Hasher>(
+        &mut self,
+        hash: usize,
+        encoded: &[u8],
+        arena: &mut Arena,
+    ) -> Result<(), TrieError> {
+        let node = Node::try_from_encoded(encoded, arena)?;
+        let children = match node {
+            Node::Branch(ref branch) => branch.keys.iter().flatten().cloned().collect(),
+            Node::Extension(ref extension) => vec![extension.key],
+            _ => Vec::new(),
+        };
+        for child in children {
+            if let Index::Hash(i) = child {
+                if arena[i].len() < H::LENGTH {
+                    let encoded = arena[i].to_vec();
+                    self.decode::<H>(i, &encoded, arena)?;
+                }
+            }
+        }
+        self.hash.insert(hash, node);
+        Ok(())
+    }
+
     pub fn get<'a>(&'a self, key: &Index) -> Option<&'a Node> {
         match key {
-            Index::Hash(ref key) => self.hash.get(key),
+            Index::Hash(ref key) => self.get_hashed(*key),
             Index::Memory(ref key) => self.memory.get(*key),
         }
     }
 
     /// Get a mutable reference to node at key
     ///
-    /// The reference index is, if needed, moved out of hash and into memory
+    /// The reference index is, if needed, moved out of hash and into memory. Nodes a
+    /// checkpoint refers to are copied instead.
     pub fn get_mut<'a>(&'a mut self, key: &mut Index) -> Option<&'a mut Node> {
         match *key {
             Index::Hash(hash) => {
-                let node = self.hash.remove(&hash)?;
+                let node = if self.checkpoints.is_empty() {
+                    self.take_hashed(hash)?
+                } else {
+                    self.get_hashed(hash)?.clone()
+                };
                 let len = self.memory.len();
                 if *key == self.root {
                     self.root = Index::Memory(len);
                 }
                 debug!("hash {} moved to memory {}", hash, len);
-                self.available_hash_slots.push(hash);
+                if hash != self.empty {
+                    if hash > self.shared {
+                        self.available_hash_slots.push(hash);
+                    }
+                    self.dereferenced.push(hash);
+                }
+                *key = Index::Memory(len);
+                self.memory.push(node);
+                self.memory.get_mut(len)
+            }
+            Index::Memory(i) if self.is_frozen(i) => {
+                let node = self.memory.get(i)?.clone();
+                let len = self.memory.len();
+                if *key == self.root {
+                    self.root = Index::Memory(len);
+                }
+                debug!("memory {} copied to {}", i, len);
                 *key = Index::Memory(len);
                 self.memory.push(node);
                 self.memory.get_mut(len)
@@ -76,6 +320,67 @@ impl Db {
         }
     }
 
+    /// Checks if a checkpoint refers to the memory node at `index`
+    fn is_frozen(&self, index: usize) -> bool {
+        self.checkpoints.last().map_or(false, |c| index < c.memory)
+    }
+
+    /// Number of open checkpoints
+    pub fn checkpoints(&self) -> usize {
+        self.checkpoints.len()
+    }
+
+    /// Takes a checkpoint and returns its id
+    pub fn checkpoint(&mut self) -> usize {
+        self.checkpoints.push(Checkpoint {
+            root: self.root,
+            memory: self.memory.len(),
+            available_hash_slots: self.available_hash_slots.len(),
+            dereferenced: self.dereferenced.len(),
+        });
+        self.checkpoints.len() - 1
+    }
+
+    /// Restores the state of checkpoint `id`, dropping it and all later checkpoints
+    pub fn revert_to(&mut self, id: usize) -> Result<(), TrieError> {
+        let checkpoint = *self
+            .checkpoints
+            .get(id)
+            .ok_or(TrieError::UnknownCheckpoint(id))?;
+        self.checkpoints.truncate(id);
+        self.root = checkpoint.root;
+        self.memory.truncate(checkpoint.memory);
+        self.available_hash_slots
+            .truncate(checkpoint.available_hash_slots);
+        self.dereferenced.truncate(checkpoint.dereferenced);
+        Ok(())
+    }
+
+    /// Drops checkpoint `id` and all later checkpoints, keeping the current state
+    pub fn discard_checkpoint(&mut self, id: usize) -> Result<(), TrieError> {
+        let checkpoint = *self
+            .checkpoints
+            .get(id)
+            .ok_or(TrieError::UnknownCheckpoint(id))?;
+        self.checkpoints.truncate(id);
+        if self.checkpoints.is_empty() {
+            // hashed nodes were only kept for the checkpoints
+            for hash in &self.dereferenced[checkpoint.dereferenced..] {
+                self.hash.remove(hash);
+            }
+        }
+        Ok(())
+    }
+
+    /// Update the reference a branch (at `nibble`) or an extension has on its child
+    pub fn set_child(&mut self, mut parent: Index, nibble: Option<u8>, child: Index) {
+        match (self.get_mut(&mut parent), nibble) {
+            (Some(Node::Branch(ref mut branch)), Some(u)) => branch.keys[u as usize] = Some(child),
+            (Some(Node::Extension(ref mut extension)), None) => extension.key = child,
+            _ => warn!("cannot set child {:?} of {:?}", child, parent),
+        }
+    }
+
     pub fn insert_node(&mut self, key: Index, value: Node) -> Option<Node> {
         debug!("inserting node {:?}", key);
         match key {
@@ -91,10 +396,26 @@ impl Db {
         index
     }
 
+    /// Removes the node at `key`
+    ///
+    /// Nodes a checkpoint refers to are left untouched and `None` is returned
     pub fn remove(&mut self, key: &Index) -> Option<Node> {
         debug!("removing node {:?}", key);
         match key {
-            Index::Hash(key) => self.hash.insert(*key, Node::Empty),
+            Index::Hash(key) => {
+                if *key != self.empty {
+                    if *key > self.shared {
+                        self.available_hash_slots.push(*key);
+                    }
+                    self.dereferenced.push(*key);
+                }
+                if self.checkpoints.is_empty() {
+                    self.take_hashed(*key)
+                } else {
+                    None
+                }
+            }
+            Index::Memory(key) if self.is_frozen(*key) => None,
             Index::Memory(key) => self
                 .memory
                 .get_mut(*key)
@@ -102,70 +423,348 @@ impl Db {
         }
     }
 
+    /// Takes the hashes of the stored nodes dereferenced since last call
+    ///
+    /// Must be called before `commit`, which may reuse their slots. Inlined nodes
+    /// are never stored hence never reported, nor are the nodes a fork got from its parent.
+    pub fn take_dereferenced<H: Hasher>(&mut self, arena: &Arena) -> Vec<Vec<u8>> {
+        let shared = self.shared;
+        self.released
+            .extend(self.dereferenced.iter().filter(|i| **i <= shared));
+        let owned = &mut self.owned;
+        self.dereferenced
+            .drain(..)
+            .filter(|i| arena[*i].len() == H::LENGTH)
+            .map(|i| arena[i].to_vec())
+            .filter(|hash| match owned {
+                None => true,
+                Some(owned) => match owned.get_mut(hash) {
+                    Some(1) => owned.remove(hash).is_some(),
+                    Some(n) => {
+                        *n -= 1;
+                        true
+                    }
+                    None => false,
+                },
+            })
+            .collect()
+    }
+
+    /// Number of nodes in memory, hashed or not
+    pub fn loaded(&self) -> usize {
+        self.hash.len() + self.memory.len()
+    }
+
+    /// Number of nodes in memory, the shared ones included
+    pub fn loaded_with_shared(&self) -> usize {
+        self.loaded()
+            + self
+                .base
+                .as_ref()
+                .map_or(0, |base| base.loaded_with_shared())
+    }
+
+    /// Rewrites the arena, keeping only the data of the nodes reachable from the root
+    ///
+    /// All nodes are moved into a new `Db` and all their indices are remapped. Hash slots
+    /// of unloaded nodes and of the nodes dereferenced since last commit are kept too.
+    /// Shared nodes are copied: the new `Db` has no base anymore. There must be no open
+    /// checkpoint.
+    pub fn compact(&mut self, arena: &mut Arena) {
+        assert!(self.checkpoints.is_empty(), "compact with open checkpoints");
+        self.rebuild(arena, None);
+    }
+
+    /// Evicts the deepest stored nodes so the remaining ones use about `budget` bytes
+    ///
+    /// Evicted nodes are compacted away and must be loaded again from the store.
+    /// Memory and inlined nodes are never evicted.
+    pub fn evict<H: Hasher>(&mut self, arena: &mut Arena, budget: usize) {
+        let mut sizes = Vec::new();
+        self.depth_sizes(self.root, 0, arena, &mut sizes);
+        let mut total = 0;
+        let depth = sizes
+            .iter()
+            .take_while(|size| {
+                total += *size;
+                total <= budget
+            })
+            .count();
+        debug!(
+            "evicting nodes deeper than {} out of {}",
+            depth,
+            sizes.len()
+        );
+        let eviction = Eviction {
+            depth,
+            hash_len: H::LENGTH,
+        };
+        self.rebuild(arena, Some(eviction));
+    }
+
+    /// Approximate memory used by the nodes at each depth
+    fn depth_sizes(&self, index: Index, depth: usize, arena: &Arena, sizes: &mut Vec<usize>) {
+        let node = match self.get(&index) {
+            Some(node) => node,
+            None => return,
+        };
+        let mut size = mem::size_of::<Node>();
+        if let Index::Hash(i) = index {
+            size += arena[i].len();
+        }
+        match node {
+            Node::Empty => (),
+            Node::Leaf(leaf) => size += arena[leaf.nibble.data].len() + arena[leaf.value].len(),
+            Node::Extension(ext) => {
+                size += arena[ext.nibble.data].len();
+                self.depth_sizes(ext.key, depth + 1, arena, sizes);
+            }
+            Node::Branch(branch) => {
+                size += mem::size_of::<Branch>() + branch.value.map_or(0, |v| arena[v].len());
+                for k in branch.keys.iter().flatten() {
+                    self.depth_sizes(*k, depth + 1, arena, sizes);
+                }
+            }
+        }
+        if sizes.len() <= depth {
+            sizes.resize(depth + 1, 0);
+        }
+        sizes[depth] += size;
+    }
+
+    /// Moves all reachable nodes, but the evicted ones, into a new `Db` and a new arena
+    fn rebuild(&mut self, arena: &mut Arena, eviction: Option<Eviction>) {
+        let mut new_arena = Arena::new();
+        let empty = new_arena.push(&arena[self.empty]);
+        let mut hash = HashMap::with_capacity(self.hash.len());
+        hash.insert(empty, Node::Empty);
+        let mut new = Db {
+            hash,
+            memory: Vec::new(),
+            available_hash_slots: Vec::new(),
+            dereferenced: self
+                .dereferenced
+                .iter()
+                .map(|i| new_arena.push(&arena[*i]))
+                .collect(),
+            checkpoints: Vec::new(),
+            empty,
+            root: Index::Hash(empty),
+            base: None,
+            shared: 0,
+            released: Vec::new(),
+            owned: self.owned.take(),
+        };
+        let root = self.root;
+        new.root = self.move_node(root, 0, eviction, arena, &mut new, &mut new_arena);
+        *self = new;
+        *arena = new_arena;
+    }
+
+    /// Move the node at `index`, and its children, into `new`
+    fn move_node(
+        &mut self,
+        index: Index,
+        depth: usize,
+        eviction: Option<Eviction>,
+        arena: &Arena,
+        new: &mut Db,
+        new_arena: &mut Arena,
+    ) -> Index {
+        let node = match index {
+            Index::Hash(i) if i == self.empty => return Index::Hash(new.empty),
+            Index::Hash(i) if eviction.map_or(false, |e| e.evicts(depth, &arena[i])) => None,
+            Index::Hash(i) => self.take_hashed(i),
+            Index::Memory(i) => self.memory.get_mut(i).map(|n| mem::replace(n, Node::Empty)),
+        };
+        let node = match (node, index) {
+            (Some(node), _) => node,
+            // not loaded, only the hash is known
+            (None, Index::Hash(i)) => return Index::Hash(new_arena.push(&arena[i])),
+            (None, Index::Memory(i)) => {
+                warn!("cannot find memory node {}", i);
+                Node::Empty
+            }
+        };
+
+        let node = match node {
+            Node::Empty => Node::Empty,
+            Node::Leaf(leaf) => Node::Leaf(Leaf {
+                nibble: leaf.nibble.copy(arena, new_arena),
+                value: new_arena.push(&arena[leaf.value]),
+            }),
+            Node::Extension(ext) => {
+                let nibble = ext.nibble.copy(arena, new_arena);
+                let key = self.move_node(ext.key, depth + 1, eviction, arena, new, new_arena);
+                Node::Extension(Extension { nibble, key })
+            }
+            Node::Branch(mut branch) => {
+                for k in branch.keys.iter_mut().flatten() {
+                    *k = self.move_node(*k, depth + 1, eviction, arena, new, new_arena);
+                }
+                branch.value = branch.value.map(|v| new_arena.push(&arena[v]));
+                Node::Branch(branch)
+            }
+        };
+
+        match index {
+            Index::Hash(i) => {
+                let i = new_arena.push(&arena[i]);
+                new.hash.insert(i, node);
+                Index::Hash(i)
+            }
+            Index::Memory(_) => new.push_node(node),
+        }
+    }
+
+    /// Saves the state changed by `take_dereferenced` and `commit`, see `revert_commit`
+    pub fn uncommitted(&self) -> Uncommitted {
+        Uncommitted {
+            root: self.root,
+            memory: Vec::new(),
+            available_hash_slots: self.available_hash_slots.clone(),
+            dereferenced: self.dereferenced.clone(),
+            released: self.released.len(),
+            replaced: Vec::new(),
+            overwritten: Vec::new(),
+        }
+    }
+
     /// Commit all the in memory nodes into hash db
-    pub fn commit(&mut self, arena: &mut Arena) -> Vec<(usize, usize)> {
+    ///
+    /// Panics if a checkpoint is open, its copies would refer to nodes moved out of memory.
+    /// The changes are recorded into `undo`, taken by
+    /// `uncommitted` before `take_dereferenced`.
+    pub fn commit<H: Hasher>(
+        &mut self,
+        arena: &mut Arena,
+        undo: &mut Uncommitted,
+    ) -> Vec<(usize, usize)> {
+        assert!(self.checkpoints.is_empty(), "commit with open checkpoints");
         let mut hashed = Vec::with_capacity(self.memory.len());
         if let Index::Hash(_) = self.root {
+            // memory nodes, if any, are leftovers of reverted changes
+            undo.memory = mem::take(&mut self.memory);
             return hashed;
         }
         let mut index = self.root;
-        self.commit_node(&mut index, arena, &mut hashed);
-        self.memory.clear();
+        self.commit_node::<H>(&mut index, arena, &mut hashed, undo);
+        undo.memory = mem::take(&mut self.memory);
         self.root = index;
+        if let Some(owned) = self.owned.as_mut() {
+            for (hash, _) in &hashed {
+                *owned.entry(arena[*hash].to_vec()).or_insert(0) += 1;
+            }
+        }
         hashed
     }
 
-    fn commit_node(
+    /// Reverts `take_dereferenced`, which returned `dereferenced`, and `commit`, which
+    /// returned `hashed`
+    pub fn revert_commit(
+        &mut self,
+        arena: &mut Arena,
+        undo: Uncommitted,
+        hashed: &[(usize, usize)],
+        dereferenced: &[Vec<u8>],
+    ) {
+        if let Some(owned) = self.owned.as_mut() {
+            for (hash, _) in hashed {
+                let hash = &arena[*hash];
+                match owned.get_mut(hash) {
+                    Some(1) => {
+                        owned.remove(hash);
+                    }
+                    Some(n) => *n -= 1,
+                    None => (),
+                }
+            }
+            for hash in dereferenced {
+                *owned.entry(hash.clone()).or_insert(0) += 1;
+            }
+        }
+        for (slot, hash) in undo.overwritten.into_iter().rev() {
+            arena.insert(slot, &hash);
+        }
+        for (slot, node) in undo.replaced.into_iter().rev() {
+            match node {
+                Some(node) => self.hash.insert(slot, node),
+                None => self.hash.remove(&slot),
+            };
+        }
+        self.root = undo.root;
+        self.memory = undo.memory;
+        self.available_hash_slots = undo.available_hash_slots;
+        self.dereferenced = undo.dereferenced;
+        self.released.truncate(undo.released);
+    }
+
+    fn commit_node<H: Hasher>(
         &mut self,
         index: &mut Index,
         arena: &mut Arena,
         hashed: &mut Vec<(usize, usize)>,
+        undo: &mut Uncommitted,
     ) {
+        // memory nodes are kept until the commit is stored
         let mut node = match *index {
             Index::Hash(_) => return,
-            Index::Memory(i) => mem::replace(&mut self.memory[i], Node::Empty),
+            Index::Memory(i) => self.memory[i].clone(),
         };
 
         let encoded_idx = match node {
             Node::Leaf(ref leaf) => leaf.encoded(arena),
             Node::Branch(ref mut branch) => {
-                for k in &mut branch.keys {
-                    if let Some(ref mut k) = k {
-                        self.commit_node(k, arena, hashed);
-                    }
+                for k in branch.keys.iter_mut().flatten() {
+                    self.commit_node::<H>(k, arena, hashed, undo);
                 }
-                branch.encoded(arena)
+                branch.encoded::<H>(arena)
             }
             Node::Extension(ref mut ext) => {
-                self.commit_node(&mut ext.key, arena, hashed);
-                ext.encoded_or_empty(arena, self.empty)
+                self.commit_node::<H>(&mut ext.key, arena, hashed, undo);
+                ext.encoded_or_empty::<H>(arena, self.empty)
+            }
+            Node::Empty if *index == self.root => {
+                // the empty trie has a well known root
+                let old = self.hash.insert(self.empty, node);
+                undo.replaced.push((self.empty, old));
+                *index = Index::Hash(self.empty);
+                return;
             }
             Node::Empty => self.empty,
         };
 
         let hash = {
             let data = &arena[encoded_idx];
-            if *index == self.root || data.len() >= H256::len() {
-                Some(keccak(data))
+            if *index == self.root || data.len() >= H::LENGTH {
+                Some(H::hash(data))
             } else {
                 None
             }
         };
 
         if let Some(hash) = hash {
-            let hash_idx = if let Some(hash_idx) = self.available_hash_slots.pop() {
-                arena.insert(hash_idx, hash.as_ref());
-                hash_idx
-            } else {
-                arena.push(hash.as_ref())
+            let hash_idx = loop {
+                match self.available_hash_slots.pop() {
+                    Some(hash_idx) if arena[hash_idx].len() == H::LENGTH => {
+                        undo.overwritten.push((hash_idx, arena[hash_idx].to_vec()));
+                        arena.insert(hash_idx, hash.as_ref());
+                        break hash_idx;
+                    }
+                    // inlined nodes slots are too small to hold a hash
+                    Some(_) => (),
+                    None => break arena.push(hash.as_ref()),
+                }
             };
-            self.hash.insert(hash_idx, node);
+            let old = self.hash.insert(hash_idx, node);
+            undo.replaced.push((hash_idx, old));
             hashed.push((hash_idx, encoded_idx));
             *index = Index::Hash(hash_idx);
         } else {
             // there is no need to save it in the database as we can directly decode it
             *index = Index::Hash(encoded_idx);
-            self.hash.insert(encoded_idx, node);
+            let old = self.hash.insert(encoded_idx, node);
+            undo.replaced.push((encoded_idx, old));
         }
     }
 }
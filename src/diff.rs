@@ -0,0 +1,291 @@
+//! Differences between two tries, walking both in lockstep
+
+use arena::Arena;
+use db::{Db, Index};
+use error::TrieError;
+use hasher::Hasher;
+use nibbles::Nibble;
+use node::Node;
+use store::NodeStore;
+
+/// A key whose value differs between two tries
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// The key is only in the new trie
+    Added { key: Vec<u8>, value: Vec<u8> },
+    /// The key is only in the old trie
+    Removed { key: Vec<u8>, value: Vec<u8> },
+    /// The key is in both tries, with different values
+    Changed {
+        key: Vec<u8>,
+        old: Vec<u8>,
+        new: Vec<u8>,
+    },
+}
+
+impl Change {
+    /// The changed key
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Change::Added { key, .. }
+            | Change::Removed { key, .. }
+            | Change::Changed { key, .. } => key,
+        }
+    }
+}
+
+/// One of the compared tries, its nodes are loaded from `store` when needed
+pub(crate) struct Side<'a, S: NodeStore> {
+    pub db: &'a mut Db,
+    pub arena: &'a mut Arena,
+    pub store: &'a S,
+}
+
+/// A position in a trie: a node and, for leaves and extensions, the number of
+/// nibbles of its path already walked
+#[derive(Debug, Clone, Copy)]
+struct Cursor {
+    index: Index,
+    skip: u32,
+}
+
+impl Cursor {
+    fn new(index: Index) -> Self {
+        Cursor { index, skip: 0 }
+    }
+}
+
+/// The value and the children at a position
+#[derive(Debug, Default)]
+struct Expanded {
+    value: Option<usize>,
+    children: [Option<Cursor>; 16],
+}
+
+impl<'a, S: NodeStore> Side<'a, S> {
+    fn expand<H: Hasher>(&mut self, cursor: Option<Cursor>) -> Result<Expanded, TrieError> {
+        let mut expanded = Expanded::default();
+        let cursor = match cursor {
+            Some(cursor) => cursor,
+            None => return Ok(expanded),
+        };
+        if let Index::Hash(h) = cursor.index {
+            if !self.db.contains(h) {
+                self.db.load::<H, _>(h, self.store, self.arena)?;
+            }
+        }
+        let rest = |nibble: &Nibble| Nibble {
+            start: nibble.start + cursor.skip,
+            ..*nibble
+        };
+        match self.db.get(&cursor.index) {
+            Some(Node::Empty) => (),
+            Some(Node::Branch(branch)) => {
+                expanded.value = branch.value;
+                expanded.children = branch.keys.map(|k| k.map(Cursor::new));
+            }
+            Some(Node::Leaf(leaf)) => match rest(&leaf.nibble).pop_front(&*self.arena) {
+                Some((u, _)) => {
+                    expanded.children[u as usize] = Some(Cursor {
+                        skip: cursor.skip + 1,
+                        ..cursor
+                    })
+                }
+                None => expanded.value = Some(leaf.value),
+            },
+            Some(Node::Extension(ext)) => match rest(&ext.nibble).pop_front(&*self.arena) {
+                // the child is reached directly, so that its hash can be compared
+                Some((u, n)) if n.is_empty() => {
+                    expanded.children[u as usize] = Some(Cursor::new(ext.key))
+                }
+                Some((u, _)) => {
+                    expanded.children[u as usize] = Some(Cursor {
+                        skip: cursor.skip + 1,
+                        ..cursor
+                    })
+                }
+                None => {
+                    let key = ext.key;
+                    return self.expand::<H>(Some(Cursor::new(key)));
+                }
+            },
+            None => return Err(TrieError::Corrupted("missing memory node")),
+        }
+        Ok(expanded)
+    }
+}
+
+/// Computes the changes from the `old` trie to the `new` one, in lexicographic key order
+///
+/// Both tries must be committed.
+pub(crate) fn diff<H, A, B>(mut new: Side<A>, mut old: Side<B>) -> Result<Vec<Change>, TrieError>
+where
+    H: Hasher,
+    A: NodeStore,
+    B: NodeStore,
+{
+    let mut changes = Vec::new();
+    let cursors = (
+        Some(Cursor::new(new.db.root_index())),
+        Some(Cursor::new(old.db.root_index())),
+    );
+    diff_at::<H, _, _>(&mut new, &mut old, cursors, &mut Vec::new(), &mut changes)?;
+    Ok(changes)
+}
+
+fn diff_at<H, A, B>(
+    new: &mut Side<A>,
+    old: &mut Side<B>,
+    (new_cursor, old_cursor): (Option<Cursor>, Option<Cursor>),
+    path: &mut Vec<u8>,
+    changes: &mut Vec<Change>,
+) -> Result<(), TrieError>
+where
+    H: Hasher,
+    A: NodeStore,
+    B: NodeStore,
+{
+    if let (Some(n), Some(o)) = (new_cursor, old_cursor) {
+        if let (0, 0, Index::Hash(n), Index::Hash(o)) = (n.skip, o.skip, n.index, o.index) {
+            // same hash (or inlined encoding), same subtrie
+            if new.arena[n] == old.arena[o] {
+                return Ok(());
+            }
+        }
+    }
+
+    let n = new.expand::<H>(new_cursor)?;
+    let o = old.expand::<H>(old_cursor)?;
+    let key = || {
+        path.chunks(2)
+            .map(|c| c[0] << 4 | c.get(1).cloned().unwrap_or(0))
+            .collect::<Vec<_>>()
+    };
+    match (n.value, o.value) {
+        (Some(v), None) => changes.push(Change::Added {
+            key: key(),
+            value: new.arena[v].to_vec(),
+        }),
+        (None, Some(v)) => changes.push(Change::Removed {
+            key: key(),
+            value: old.arena[v].to_vec(),
+        }),
+        (Some(v), Some(w)) if new.arena[v] != old.arena[w] => changes.push(Change::Changed {
+            key: key(),
+            old: old.arena[w].to_vec(),
+            new: new.arena[v].to_vec(),
+        }),
+        _ => (),
+    }
+
+    for (u, cursors) in n.children.iter().zip(o.children.iter()).enumerate() {
+        if let (None, None) = cursors {
+            continue;
+        }
+        path.push(u as u8);
+        diff_at::<H, _, _>(new, old, (*cursors.0, *cursors.1), path, changes)?;
+        path.pop();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use keccak_hash::KECCAK_NULL_RLP;
+    use std::collections::{BTreeMap, BTreeSet, HashMap};
+    use testutil::mixed_sample;
+    use trie::Trie;
+
+    fn items(n: usize) -> BTreeMap<Vec<u8>, Vec<u8>> {
+        mixed_sample(n).into_iter().collect()
+    }
+
+    /// The expected changes, by comparing all items
+    fn expected(old: &BTreeMap<Vec<u8>, Vec<u8>>, new: &BTreeMap<Vec<u8>, Vec<u8>>) -> Vec<Change> {
+        let keys = old.keys().chain(new.keys()).collect::<BTreeSet<_>>();
+        keys.into_iter()
+            .filter_map(|key| match (old.get(key), new.get(key)) {
+                (None, Some(value)) => Some(Change::Added {
+                    key: key.clone(),
+                    value: value.clone(),
+                }),
+                (Some(value), None) => Some(Change::Removed {
+                    key: key.clone(),
+                    value: value.clone(),
+                }),
+                (Some(o), Some(n)) if o != n => Some(Change::Changed {
+                    key: key.clone(),
+                    old: o.clone(),
+                    new: n.clone(),
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn diff_root() {
+        let old = items(300);
+        let mut store = HashMap::new();
+        let old_root = {
+            let mut t = Trie::with_store(&mut store);
+            for (k, v) in &old {
+                t.insert(k, v).unwrap();
+            }
+            t.root().unwrap().to_vec()
+        };
+
+        let mut new = old.clone();
+        let mut t = Trie::from_root(&old_root, &mut store);
+        assert_eq!(t.diff(&old_root).unwrap(), Vec::new());
+        for (i, (k, v)) in new.iter_mut().enumerate() {
+            if i % 50 == 0 {
+                *v = vec![0x42; 1 + i % 60];
+                t.insert(k, &v).unwrap();
+            }
+        }
+        for k in new.keys().cloned().step_by(70).collect::<Vec<_>>() {
+            new.remove(&k);
+            t.remove(&k).unwrap();
+        }
+        for i in 0..5u8 {
+            new.insert(vec![i, 0xff, i], vec![i; 3]);
+            t.insert([i, 0xff, i], [i; 3]).unwrap();
+        }
+        let changes = t.diff(&old_root).unwrap();
+        assert_eq!(changes, expected(&old, &new));
+        assert!(changes.windows(2).all(|w| w[0].key() < w[1].key()));
+
+        // identical subtries are never loaded
+        assert!(t.db().loaded() < old.len() / 2, "{}", t.db().loaded());
+
+        assert_eq!(
+            t.diff(&KECCAK_NULL_RLP).unwrap(),
+            expected(&BTreeMap::new(), &new)
+        );
+    }
+
+    #[test]
+    fn diff_trie() {
+        let old = items(200);
+        let mut new = items(230);
+        new.retain(|k, _| k[0] != 3);
+        new.insert(vec![0x01], vec![0x42]);
+
+        let mut a = Trie::new();
+        for (k, v) in &old {
+            a.insert(k, v).unwrap();
+        }
+        let mut b = Trie::new();
+        for (k, v) in &new {
+            b.insert(k, v).unwrap();
+        }
+        assert_eq!(b.diff_trie(&mut a).unwrap(), expected(&old, &new));
+        assert_eq!(a.diff_trie(&mut b).unwrap(), expected(&new, &old));
+        assert_eq!(
+            a.diff_trie(&mut Trie::new()).unwrap(),
+            expected(&BTreeMap::new(), &old)
+        );
+    }
+}
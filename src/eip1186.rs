@@ -0,0 +1,240 @@
+//! `eth_getProof` (EIP-1186) responses
+//!
+//! Builds the `accountProof` and `storageProof` structures out of a state trie and the
+//! storage trie of the account. As in ethereum, state and storage keys are the keccak
+//! hashes of the address and of the storage slot.
+
+use error::TrieError;
+use keccak_hash::{keccak, KECCAK_EMPTY, KECCAK_NULL_RLP};
+use rlp::Rlp;
+use store::NodeStore;
+use trie::Trie;
+
+/// The proof of an account and of some of its storage slots
+///
+/// All fields are hex encoded the way the json rpc expects them
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountProof {
+    pub address: String,
+    pub account_proof: Vec<String>,
+    pub balance: String,
+    pub code_hash: String,
+    pub nonce: String,
+    pub storage_hash: String,
+    pub storage_proof: Vec<StorageProof>,
+}
+
+/// The proof of a storage slot
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageProof {
+    pub key: String,
+    pub value: String,
+    pub proof: Vec<String>,
+}
+
+/// Get the proof of the account at `address` and of its storage `keys`
+///
+/// `storage` is the storage trie of the account (an empty trie if the account doesn't exist),
+/// its root must match the account storage root or `StorageRootMismatch` is returned.
+pub fn get_proof<S, T, K>(
+    state: &mut Trie<S>,
+    storage: &mut Trie<T>,
+    address: &[u8],
+    keys: &[K],
+) -> Result<AccountProof, TrieError>
+where
+    S: NodeStore,
+    T: NodeStore,
+    K: AsRef<[u8]>,
+{
+    let state_key = keccak(address);
+    let account_proof = state.get_proof(state_key)?.iter().map(|n| hex(n)).collect();
+    let (nonce, balance, storage_hash, code_hash) = match state.get_loading(state_key)? {
+        Some(account) => {
+            let r = Rlp::new(account);
+            (
+                quantity(r.at(0)?.data()?),
+                quantity(r.at(1)?.data()?),
+                r.at(2)?.data()?.to_vec(),
+                r.at(3)?.data()?.to_vec(),
+            )
+        }
+        None => (
+            quantity(&[]),
+            quantity(&[]),
+            KECCAK_NULL_RLP.to_vec(),
+            KECCAK_EMPTY.to_vec(),
+        ),
+    };
+
+    if storage.root()? != &*storage_hash {
+        return Err(TrieError::StorageRootMismatch(storage_hash));
+    }
+
+    let storage_proof = keys
+        .iter()
+        .map(|key| {
+            let key = key.as_ref();
+            let storage_key = keccak(key);
+            let proof = storage
+                .get_proof(storage_key)?
+                .iter()
+                .map(|n| hex(n))
+                .collect();
+            let value = match storage.get_loading(storage_key)? {
+                Some(value) => quantity(Rlp::new(value).data()?),
+                None => quantity(&[]),
+            };
+            Ok(StorageProof {
+                key: hex(key),
+                value,
+                proof,
+            })
+        })
+        .collect::<Result<_, TrieError>>()?;
+
+    Ok(AccountProof {
+        address: hex(address),
+        account_proof,
+        balance,
+        code_hash: hex(&code_hash),
+        nonce,
+        storage_hash: hex(&storage_hash),
+        storage_proof,
+    })
+}
+
+/// Hex encode data (DATA in json rpc)
+fn hex(data: &[u8]) -> String {
+    let mut s = String::with_capacity(2 + data.len() * 2);
+    s.push_str("0x");
+    for b in data {
+        s.push_str(&format!("{:02x}", b));
+    }
+    s
+}
+
+/// Hex encode a big endian number without leading zeros (QUANTITY in json rpc)
+fn quantity(data: &[u8]) -> String {
+    let data = match data.iter().position(|b| *b != 0) {
+        Some(i) => &data[i..],
+        None => return "0x0".to_string(),
+    };
+    let hex = hex(data);
+    if data[0] < 0x10 {
+        format!("0x{}", &hex[3..])
+    } else {
+        hex
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proof::verify_proof;
+    use rlp::RlpStream;
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (2..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn account(nonce: &[u8], balance: &[u8], storage_root: &[u8]) -> Vec<u8> {
+        let mut stream = RlpStream::new_list(4);
+        stream
+            .append(&nonce)
+            .append(&balance)
+            .append(&storage_root)
+            .append(&&KECCAK_EMPTY[..]);
+        stream.out()
+    }
+
+    #[test]
+    fn quantities() {
+        assert_eq!(quantity(&[]), "0x0");
+        assert_eq!(quantity(&[0, 0]), "0x0");
+        assert_eq!(quantity(&[0, 0x01, 0x23]), "0x123");
+        assert_eq!(quantity(&[0x12, 0x03]), "0x1203");
+        assert_eq!(hex(&[0x01, 0xab]), "0x01ab");
+    }
+
+    #[test]
+    fn account_proof() {
+        let address = [0x11u8; 20];
+        let slots = [[0u8; 32], [1u8; 32], [2u8; 32]];
+
+        let mut storage = Trie::new();
+        storage
+            .insert(keccak(slots[0]), rlp::encode(&vec![0x04u8, 0x00]))
+            .unwrap();
+        storage
+            .insert(keccak(slots[1]), rlp::encode(&vec![0x2au8]))
+            .unwrap();
+        let storage_root = storage.root().unwrap().to_vec();
+
+        let mut state = Trie::new();
+        for i in 0..20u8 {
+            let other = account(&[i], &[i, 0], &KECCAK_NULL_RLP);
+            state.insert(keccak([i; 20]), other).unwrap();
+        }
+        let value = account(&[0x07], &[0x0d, 0xe0, 0xb6], &storage_root);
+        state.insert(keccak(address), &value).unwrap();
+        let state_root = state.root().unwrap().to_vec();
+
+        let proof = get_proof(&mut state, &mut storage, &address, &slots).unwrap();
+        assert_eq!(proof.address, hex(&address));
+        assert_eq!(proof.nonce, "0x7");
+        assert_eq!(proof.balance, "0xde0b6");
+        assert_eq!(proof.storage_hash, hex(&storage_root));
+        assert_eq!(proof.code_hash, hex(&KECCAK_EMPTY));
+
+        let nodes = proof
+            .account_proof
+            .iter()
+            .map(|n| unhex(n))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            verify_proof(&state_root, keccak(address), &nodes),
+            Ok(Some(value))
+        );
+
+        let values = ["0x400", "0x2a", "0x0"];
+        for ((p, slot), value) in proof.storage_proof.iter().zip(&slots).zip(&values) {
+            assert_eq!(p.key, hex(slot));
+            assert_eq!(p.value, *value);
+            let nodes = p.proof.iter().map(|n| unhex(n)).collect::<Vec<_>>();
+            let proven = verify_proof(&storage_root, keccak(slot), &nodes).unwrap();
+            assert_eq!(proven.is_some(), *value != "0x0");
+        }
+
+        // the storage trie must be the one of the account
+        storage.remove(keccak(slots[1])).unwrap();
+        assert_eq!(
+            get_proof(&mut state, &mut storage, &address, &slots),
+            Err(TrieError::StorageRootMismatch(storage_root))
+        );
+    }
+
+    #[test]
+    fn missing_account() {
+        let mut state = Trie::new();
+        state
+            .insert(keccak([0x01; 20]), account(&[1], &[1], &KECCAK_NULL_RLP))
+            .unwrap();
+        let proof = get_proof(&mut state, &mut Trie::new(), &[0x02; 20], &[[0u8; 32]]).unwrap();
+        assert_eq!(proof.nonce, "0x0");
+        assert_eq!(proof.balance, "0x0");
+        assert_eq!(proof.storage_hash, hex(&KECCAK_NULL_RLP));
+        assert_eq!(proof.account_proof.len(), 1);
+        assert_eq!(proof.storage_proof[0].value, "0x0");
+
+        let mut storage = Trie::new();
+        storage.insert([0x01], [0x01]).unwrap();
+        assert_eq!(
+            get_proof(&mut state, &mut storage, &[0x02; 20], &[[0u8; 32]]),
+            Err(TrieError::StorageRootMismatch(KECCAK_NULL_RLP.to_vec()))
+        );
+    }
+}
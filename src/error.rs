@@ -0,0 +1,80 @@
+use rlp::DecoderError;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// An error occuring while reading or writing a `Trie`
+#[derive(Debug, Clone, PartialEq)]
+pub enum TrieError {
+    /// A node (identified by its hash) is neither in memory nor in the store
+    MissingNode(Vec<u8>),
+    /// A node is not valid rlp
+    InvalidRlp(DecoderError),
+    /// An encoded nibble starts with an unknown prefix
+    InvalidNibblePrefix(u8),
+    /// The trie structure is not consistent
+    Corrupted(&'static str),
+    /// The backend store failed
+    Store(String),
+    /// A key is not strictly greater than the previously inserted one
+    UnorderedKey(Vec<u8>),
+    /// The checkpoint doesn't exist (anymore)
+    UnknownCheckpoint(usize),
+    /// Cannot commit while checkpoints are open
+    PendingCheckpoints(usize),
+    /// The storage trie root is not the storage root of the account (the latter is given)
+    StorageRootMismatch(Vec<u8>),
+}
+
+impl fmt::Display for TrieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrieError::MissingNode(hash) => {
+                write!(f, "missing node ")?;
+                for b in hash {
+                    write!(f, "{:02x}", b)?;
+                }
+                Ok(())
+            }
+            TrieError::InvalidRlp(e) => write!(f, "invalid rlp node: {}", e),
+            TrieError::InvalidNibblePrefix(p) => write!(f, "invalid nibble prefix {:X}", p),
+            TrieError::Corrupted(msg) => write!(f, "corrupted trie: {}", msg),
+            TrieError::Store(msg) => write!(f, "store error: {}", msg),
+            TrieError::UnorderedKey(key) => {
+                write!(f, "key ")?;
+                for b in key {
+                    write!(f, "{:02x}", b)?;
+                }
+                write!(f, " is not greater than the previous one")
+            }
+            TrieError::UnknownCheckpoint(id) => write!(f, "unknown checkpoint {}", id),
+            TrieError::PendingCheckpoints(n) => {
+                write!(f, "cannot commit with {} open checkpoints", n)
+            }
+            TrieError::StorageRootMismatch(root) => {
+                write!(
+                    f,
+                    "storage trie root doesn't match the account storage root "
+                )?;
+                for b in root {
+                    write!(f, "{:02x}", b)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for TrieError {}
+
+impl From<DecoderError> for TrieError {
+    fn from(e: DecoderError) -> Self {
+        TrieError::InvalidRlp(e)
+    }
+}
+
+impl From<io::Error> for TrieError {
+    fn from(e: io::Error) -> Self {
+        TrieError::Store(e.to_string())
+    }
+}
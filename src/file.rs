@@ -0,0 +1,364 @@
+//! A `NodeStore` persisting nodes into a local append-only file
+
+use error::TrieError;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use store::NodeStore;
+
+/// Record header: hash length (1 byte) then encoded node length (4 bytes, little endian)
+const HEADER_LEN: u64 = 5;
+
+/// Encoded node length of a tombstone, a record deleting the node with its hash
+const TOMBSTONE: u32 = u32::MAX;
+
+/// A `NodeStore` appending (hash, encoded node) records to a file
+///
+/// The hashes are indexed in memory when opening the file, nodes are read back from the
+/// file when needed. A record partially written (e.g. on a crash) is discarded on open.
+///
+/// The root is not stored: keep it aside to reopen the trie with `Trie::from_root`.
+/// Deleting a node appends a tombstone, the space is only reclaimed by `compact`.
+#[derive(Debug)]
+pub struct FileStore {
+    file: File,
+    path: PathBuf,
+    /// Position and length of the encoded nodes in the file, by hash
+    index: HashMap<Vec<u8>, (u64, u32)>,
+    /// Length of the valid records
+    len: u64,
+    /// Length of the records of deleted nodes, tombstones included
+    garbage: u64,
+}
+
+impl FileStore {
+    /// Opens the store at `path`, creating the file if it doesn't exist
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, TrieError> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let file_len = file.metadata()?.len();
+        let mut index = HashMap::new();
+        let mut len = 0;
+        let mut garbage = 0;
+        {
+            let mut reader = BufReader::new(&file);
+            let mut header = [0; HEADER_LEN as usize];
+            while len + HEADER_LEN <= file_len {
+                reader.read_exact(&mut header)?;
+                let hash_len = u64::from(header[0]);
+                let node_len = u32::from_le_bytes([header[1], header[2], header[3], header[4]]);
+                let data_len = if node_len == TOMBSTONE {
+                    0
+                } else {
+                    u64::from(node_len)
+                };
+                let end = len + HEADER_LEN + hash_len + data_len;
+                if end > file_len {
+                    break;
+                }
+                let mut hash = vec![0; hash_len as usize];
+                reader.read_exact(&mut hash)?;
+                if node_len == TOMBSTONE {
+                    if let Some((_, node_len)) = index.remove(&hash) {
+                        garbage += record_len(hash.len(), node_len);
+                    }
+                    garbage += end - len;
+                } else {
+                    reader.seek_relative(i64::from(node_len))?;
+                    index.insert(hash, (end - data_len, node_len));
+                }
+                len = end;
+            }
+        }
+        if len < file_len {
+            warn!("discarding {} bytes of incomplete record", file_len - len);
+            file.set_len(len)?;
+        }
+        Ok(FileStore {
+            file,
+            path,
+            index,
+            len,
+            garbage,
+        })
+    }
+
+    /// Number of nodes in the store
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Returns true if the store has no node
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Flush all written nodes to the disk
+    pub fn sync(&self) -> Result<(), TrieError> {
+        Ok(self.file.sync_data()?)
+    }
+
+    /// Number of bytes `compact` would reclaim
+    pub fn garbage(&self) -> u64 {
+        self.garbage
+    }
+
+    /// Rewrites the file without the deleted nodes
+    ///
+    /// The live records are copied into a temporary file next to the store, which then
+    /// replaces it.
+    pub fn compact(&mut self) -> Result<(), TrieError> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".compact");
+        let tmp_path = PathBuf::from(tmp_path);
+        let mut index = HashMap::with_capacity(self.index.len());
+        let mut len = 0;
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            for (hash, &(pos, node_len)) in &self.index {
+                let mut encoded = vec![0; node_len as usize];
+                let mut file = &self.file;
+                file.seek(SeekFrom::Start(pos))?;
+                file.read_exact(&mut encoded)?;
+                writer.write_all(&[hash.len() as u8])?;
+                writer.write_all(&node_len.to_le_bytes())?;
+                writer.write_all(hash)?;
+                writer.write_all(&encoded)?;
+                len += HEADER_LEN + hash.len() as u64;
+                index.insert(hash.clone(), (len, node_len));
+                len += u64::from(node_len);
+            }
+            writer.flush()?;
+            writer.get_ref().sync_data()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+        self.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        self.index = index;
+        self.len = len;
+        self.garbage = 0;
+        Ok(())
+    }
+}
+
+/// Length of the record of a node
+fn record_len(hash_len: usize, node_len: u32) -> u64 {
+    HEADER_LEN + hash_len as u64 + u64::from(node_len)
+}
+
+impl NodeStore for FileStore {
+    fn get(&self, hash: &[u8]) -> Result<Option<Vec<u8>>, TrieError> {
+        let (pos, len) = match self.index.get(hash) {
+            Some(&location) => location,
+            None => return Ok(None),
+        };
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(pos))?;
+        let mut encoded = vec![0; len as usize];
+        file.read_exact(&mut encoded)?;
+        Ok(Some(encoded))
+    }
+
+    fn put(&mut self, hash: &[u8], encoded: &[u8]) -> Result<(), TrieError> {
+        self.put_batch(&[(hash, encoded)])
+    }
+
+    /// Append all new nodes at once, nodes already in the store are skipped
+    fn put_batch(&mut self, nodes: &[(&[u8], &[u8])]) -> Result<(), TrieError> {
+        if nodes.iter().any(|(hash, encoded)| {
+            hash.len() > u8::MAX as usize || encoded.len() >= TOMBSTONE as usize
+        }) {
+            return Err(TrieError::Store("node too large".to_string()));
+        }
+
+        let mut buffer = Vec::new();
+        let mut added = Vec::new();
+        for (hash, encoded) in nodes {
+            if self.index.contains_key(*hash) {
+                continue;
+            }
+            buffer.push(hash.len() as u8);
+            buffer.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+            buffer.extend_from_slice(hash);
+            let pos = self.len + buffer.len() as u64;
+            buffer.extend_from_slice(encoded);
+            self.index
+                .insert(hash.to_vec(), (pos, encoded.len() as u32));
+            added.push(*hash);
+        }
+        if buffer.is_empty() {
+            return Ok(());
+        }
+        if let Err(e) = self.file.write_all(&buffer) {
+            for hash in added {
+                self.index.remove(hash);
+            }
+            // best effort, the partial record would be discarded on open anyway
+            let _ = self.file.set_len(self.len);
+            return Err(e.into());
+        }
+        self.len += buffer.len() as u64;
+        Ok(())
+    }
+
+    /// Append a tombstone, the node is dropped from the file by `compact`
+    fn delete(&mut self, hash: &[u8]) -> Result<(), TrieError> {
+        let node_len = match self.index.get(hash) {
+            Some(&(_, node_len)) => node_len,
+            None => return Ok(()),
+        };
+        let mut buffer = vec![hash.len() as u8];
+        buffer.extend_from_slice(&TOMBSTONE.to_le_bytes());
+        buffer.extend_from_slice(hash);
+        if let Err(e) = self.file.write_all(&buffer) {
+            let _ = self.file.set_len(self.len);
+            return Err(e.into());
+        }
+        self.index.remove(hash);
+        self.len += buffer.len() as u64;
+        self.garbage += buffer.len() as u64 + record_len(hash.len(), node_len);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use refcount::RefCountStore;
+    use testutil::{sample, temp_path};
+    use trie::Trie;
+
+    #[test]
+    fn reopen() {
+        let path = temp_path("reopen");
+        let items = sample(100);
+        let root = {
+            let mut t = Trie::with_store(FileStore::open(&path).unwrap());
+            for (k, v) in &items {
+                t.insert(k, v).unwrap();
+            }
+            let root = t.root().unwrap().to_vec();
+            t.store().sync().unwrap();
+            root
+        };
+
+        let store = FileStore::open(&path).unwrap();
+        assert!(!store.is_empty());
+        let mut t = Trie::from_root(&root, store);
+        for (k, v) in &items {
+            assert_eq!(t.get_loading(k).unwrap(), Some(&**v));
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn incomplete_record() {
+        let path = temp_path("incomplete");
+        let len = {
+            let mut store = FileStore::open(&path).unwrap();
+            store.put(&[1; 32], &[0xc2, 0x01, 0x02]).unwrap();
+            // already stored nodes are skipped
+            store.put(&[1; 32], &[0xc2, 0x01, 0x02]).unwrap();
+            assert_eq!(store.len(), 1);
+            store.len
+        };
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+
+        // simulate a crash in the middle of a write
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[32, 3, 0, 0, 0, 2, 2]).unwrap();
+
+        let mut store = FileStore::open(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        assert_eq!(store.get(&[1; 32]).unwrap(), Some(vec![0xc2, 0x01, 0x02]));
+        assert_eq!(store.get(&[2; 32]).unwrap(), None);
+        store.put(&[2; 32], &[0xc2, 0x03, 0x04]).unwrap();
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get(&[2; 32]).unwrap(), Some(vec![0xc2, 0x03, 0x04]));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn delete() {
+        let path = temp_path("delete");
+        let mut store = FileStore::open(&path).unwrap();
+        for i in 1..4 {
+            store.put(&[i; 32], &[0xc2, i, i]).unwrap();
+        }
+        store.delete(&[2; 32]).unwrap();
+        // unknown nodes are ignored
+        store.delete(&[4; 32]).unwrap();
+        assert_eq!(store.get(&[2; 32]).unwrap(), None);
+        assert_eq!(store.len(), 2);
+        let garbage = store.garbage();
+        assert!(garbage > 0);
+
+        let mut store = FileStore::open(&path).unwrap();
+        assert_eq!(store.get(&[2; 32]).unwrap(), None);
+        assert_eq!(store.garbage(), garbage);
+        let len = store.len;
+        store.compact().unwrap();
+        assert_eq!(store.garbage(), 0);
+        assert_eq!(store.len, len - garbage);
+        assert_eq!(fs::metadata(&path).unwrap().len(), len - garbage);
+        assert_eq!(store.get(&[3; 32]).unwrap(), Some(vec![0xc2, 3, 3]));
+
+        // a deleted node can be written again
+        store.put(&[2; 32], &[0xc2, 2, 2]).unwrap();
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.len(), 3);
+        for i in 1..4 {
+            assert_eq!(store.get(&[i; 32]).unwrap(), Some(vec![0xc2, i, i]));
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn prune() {
+        let path = temp_path("prune");
+        let mut items = sample(100);
+        let mut store = RefCountStore::new(FileStore::open(&path).unwrap(), 0);
+        let root = {
+            let mut t = Trie::with_store(&mut store);
+            for (k, v) in &items {
+                t.insert(k, v).unwrap();
+            }
+            t.commit().unwrap();
+            for (i, (k, v)) in items.iter_mut().enumerate() {
+                if i % 2 == 0 {
+                    *v = vec![0x42; 1 + i % 40];
+                    t.insert(k, &v).unwrap();
+                }
+            }
+            t.root().unwrap().to_vec()
+        };
+
+        // only the nodes of the last trie are left
+        let mut expected = HashMap::new();
+        {
+            let mut t = Trie::with_store(&mut expected);
+            for (k, v) in &items {
+                t.insert(k, v).unwrap();
+            }
+        }
+        let mut store = store.into_store();
+        assert_eq!(store.len(), expected.len());
+        assert!(store.garbage() > 0);
+        store.compact().unwrap();
+
+        let mut t = Trie::from_root(&root, FileStore::open(&path).unwrap());
+        for (k, v) in &items {
+            assert_eq!(t.get_loading(k).unwrap(), Some(&**v));
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
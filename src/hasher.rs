@@ -0,0 +1,52 @@
+//! Hash functions referencing trie nodes
+
+use keccak_hash::{keccak, H256, KECCAK_NULL_RLP};
+use rlp::NULL_RLP;
+
+/// A hash function used to reference trie nodes
+pub trait Hasher {
+    /// A hash, always `LENGTH` bytes long
+    type Out: AsRef<[u8]>;
+
+    /// The length of the hashes
+    ///
+    /// Nodes whose encoding is shorter are inlined into their parent
+    const LENGTH: usize;
+
+    /// Hash some data
+    fn hash(data: &[u8]) -> Self::Out;
+
+    /// The hash of the empty trie, i.e. of the rlp encoded empty node
+    fn null_node() -> Self::Out {
+        Self::hash(&NULL_RLP)
+    }
+}
+
+/// The ethereum keccak-256 hasher
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Keccak;
+
+impl Hasher for Keccak {
+    type Out = H256;
+
+    const LENGTH: usize = 32;
+
+    fn hash(data: &[u8]) -> H256 {
+        keccak(data)
+    }
+
+    fn null_node() -> H256 {
+        KECCAK_NULL_RLP
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keccak_null_node() {
+        assert_eq!(Keccak::null_node(), Keccak::hash(&NULL_RLP));
+        assert_eq!(Keccak::hash(&[])[..].len(), Keccak::LENGTH);
+    }
+}
@@ -1,108 +1,611 @@
-use node::{Branch, Extension, Leaf, Node};
+use arena::{Arena, ArenaSlice};
+use db::{Db, Index};
+use error::TrieError;
+use hasher::Hasher;
+use nibbles::Nibble;
+use node::{Branch, Leaf, Node};
 use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::ops::Bound;
+use store::NodeStore;
 use trie::Trie;
 
 /// A Depth First Search iterator
 ///
-/// Early stops if a node has not been commited
+/// Iterates over both committed and in-memory (not yet committed) nodes. Nodes are not
+/// loaded from the store: the iteration stops at the first missing one, see
+/// `Trie::iter_loading` to load them on the fly.
+///
+/// Items are yielded in lexicographic key order, or in descending order if `reversed`.
 pub struct DFSIter<'a> {
-    stack: Vec<NodeIter<'a>>,
-    trie: &'a Trie,
-    root: bool,
+    cursor: Cursor,
+    db: &'a Db,
+    arena: &'a Arena,
 }
 
-enum NodeIter<'a> {
-    Branch(&'a Branch, Option<u8>),
-    Extension(&'a Extension),
+/// The position of a depth first search
+///
+/// Only node indices are kept, so that missing nodes can be loaded between two steps.
+struct Cursor {
+    stack: Vec<Frame>,
+    /// The root of the iterated (sub)trie
+    root: Option<Index>,
+    /// The node to start from, if not started yet
+    start: Option<Index>,
+    /// The nibbles leading to the root
+    prefix: Vec<u8>,
+    /// Iterates in descending key order
+    reverse: bool,
 }
 
-impl<'a> DFSIter<'a> {
-    pub fn new(trie: &'a Trie) -> Self {
-        DFSIter {
+#[derive(Clone, Copy)]
+enum Frame {
+    /// A branch and the nibble of the child being iterated, if any
+    ///
+    /// When iterating in reverse, only the children before that nibble and
+    /// the branch value are left
+    Branch(Index, Option<u8>),
+    Extension(Index),
+}
+
+/// The next node to visit when iterating in reverse
+enum Previous {
+    Child(Index),
+    Value(usize),
+    Done,
+}
+
+/// The branch at `index`, which has been visited already
+fn branch<'a>(db: &'a Db, index: &Index) -> Option<&'a Branch> {
+    match db.get(index) {
+        Some(Node::Branch(ref branch)) => Some(branch),
+        _ => None,
+    }
+}
+
+impl Cursor {
+    fn new(root: Index) -> Self {
+        Cursor {
             stack: Vec::new(),
-            root: true,
-            trie,
+            root: Some(root),
+            start: Some(root),
+            prefix: Vec::new(),
+            reverse: false,
         }
     }
 
-    fn build_key(&self, leaf: Option<&Leaf>) -> Cow<'a, [u8]> {
+    /// Descends from the root to the first node covering the whole prefix
+    fn with_prefix(root: Index, db: &Db, arena: &Arena, prefix: &[u8]) -> Self {
+        let mut cursor = Cursor::new(root);
+        let data = &[prefix];
+        let prefix_arena = &ArenaSlice(data.as_ref());
+        let mut path = Nibble {
+            data: 0,
+            start: 0,
+            end: prefix.len() as u32 * 2,
+        };
+
+        cursor.start = loop {
+            let key = match cursor.start {
+                Some(key) => key,
+                None => break None,
+            };
+            match db.get(&key) {
+                Some(Node::Branch(ref branch)) => match path.pop_front(prefix_arena) {
+                    Some((u, n)) => {
+                        cursor.prefix.push(u);
+                        cursor.start = branch.keys[u as usize];
+                        path = n;
+                    }
+                    None => break Some(key),
+                },
+                Some(Node::Extension(ref extension)) => {
+                    let (left, right) = path.split_at(extension.nibble.len());
+                    let (ext_left, _) = extension.nibble.split_at(left.len());
+                    if !ext_left.eq(&left, arena, prefix_arena) {
+                        break None;
+                    }
+                    match right {
+                        // the prefix ends within the extension
+                        None if left.len() < extension.nibble.len() => break Some(key),
+                        right => {
+                            cursor.prefix.extend(extension.nibble.iter(arena));
+                            cursor.start = Some(extension.key);
+                            path = right.unwrap_or_default();
+                        }
+                    }
+                }
+                Some(Node::Leaf(ref leaf)) => {
+                    let (leaf_left, _) = leaf.nibble.split_at(path.len());
+                    if leaf_left.eq(&path, arena, prefix_arena) {
+                        break Some(key);
+                    } else {
+                        break None;
+                    }
+                }
+                Some(Node::Empty) | None => break None,
+            }
+        };
+        cursor.root = cursor.start;
+        cursor
+    }
+
+    fn reversed(&mut self) {
+        self.reverse = !self.reverse;
+        self.stack.clear();
+        self.start = self.root;
+    }
+
+    fn seek(&mut self, db: &Db, arena: &Arena, key: &[u8]) {
+        let data = &[key];
+        let key_arena = &ArenaSlice(data.as_ref());
+        let path = Nibble {
+            data: 0,
+            start: 0,
+            end: key.len() as u32 * 2,
+        };
+
+        self.stack.clear();
+        self.start = None;
+        let prefix_len = self.prefix.len();
+        match path
+            .iter(key_arena)
+            .take(prefix_len)
+            .cmp(self.prefix.iter().cloned())
+        {
+            // all items are after (before if reverse) the key
+            Ordering::Less if !self.reverse => self.start = self.root,
+            Ordering::Greater if self.reverse => self.start = self.root,
+            // no item is
+            Ordering::Less | Ordering::Greater => (),
+            Ordering::Equal => {
+                let (_, path) = path.split_at(prefix_len as u32);
+                if let Some(root) = self.root {
+                    self.seek_from(db, arena, root, path.unwrap_or_default(), key_arena);
+                }
+            }
+        }
+    }
+
+    /// Descends along `path` from node `key`, stacking nodes before `path`
+    /// so the next item is the first one greater (lower if reverse) or equal to it
+    fn seek_from<A>(&mut self, db: &Db, arena: &Arena, mut key: Index, mut path: Nibble, a: &A)
+    where
+        A: ::std::ops::Index<usize, Output = [u8]>,
+    {
+        loop {
+            match db.get(&key) {
+                Some(Node::Branch(ref branch)) => match path.pop_front(a) {
+                    Some((u, n)) => {
+                        // the branch value, if any, is before the path
+                        self.stack.push(Frame::Branch(key, Some(u)));
+                        match branch.keys[u as usize] {
+                            Some(k) => key = k,
+                            None => return,
+                        }
+                        path = n;
+                    }
+                    None if self.reverse => {
+                        // only the branch value is not after the path
+                        self.stack.push(Frame::Branch(key, Some(0)));
+                        return;
+                    }
+                    None => break,
+                },
+                Some(Node::Extension(ref extension)) => {
+                    let len = extension.nibble.len();
+                    let ord = extension
+                        .nibble
+                        .iter(arena)
+                        .cmp(path.iter(a).take(len as usize));
+                    match (ord, self.reverse) {
+                        (Ordering::Equal, false) if path.len() > len => (),
+                        (Ordering::Equal, true) => (),
+                        (Ordering::Less, false) | (Ordering::Greater, true) => return,
+                        _ => break,
+                    }
+                    self.stack.push(Frame::Extension(key));
+                    key = extension.key;
+                    path = path.split_at(len).1.unwrap_or_default();
+                }
+                Some(Node::Leaf(ref leaf)) => {
+                    match (leaf.nibble.iter(arena).cmp(path.iter(a)), self.reverse) {
+                        (Ordering::Less, false) | (Ordering::Greater, true) => return,
+                        _ => break,
+                    }
+                }
+                Some(Node::Empty) | None => return,
+            }
+        }
+        self.start = Some(key);
+    }
+
+    fn build_key(&self, db: &Db, arena: &Arena, leaf: Option<&Leaf>) -> Vec<u8> {
         let mut buffer = Vec::with_capacity(64);
+        buffer.extend_from_slice(&self.prefix);
         for n in &self.stack {
-            match n {
-                NodeIter::Branch(_, Some(n)) => {
-                    debug!("one branch");
-                    buffer.push(*n);
-                }
-                NodeIter::Extension(e) => {
-                    debug!("one extension {}", e.nibble.len());
-                    buffer.extend(e.nibble.iter(self.trie.arena()));
+            match *n {
+                Frame::Branch(_, Some(n)) => buffer.push(n),
+                Frame::Extension(ref key) => {
+                    if let Some(Node::Extension(ref e)) = db.get(key) {
+                        buffer.extend(e.nibble.iter(arena));
+                    }
                 }
                 _ => (),
             }
         }
         if let Some(leaf) = leaf {
-            buffer.extend(leaf.nibble.iter(self.trie.arena()));
+            buffer.extend(leaf.nibble.iter(arena));
         }
-        debug!("buffer len: {}", buffer.len());
         debug!("buffer {:?}", buffer);
-        Cow::Owned(buffer.chunks(2).map(|w| w[0] << 4 | w[1]).collect())
+        buffer.chunks(2).map(|w| w[0] << 4 | w[1]).collect()
     }
 
-    fn branch_item(&self, value: usize) -> (Cow<'a, [u8]>, &'a [u8]) {
-        debug!("getting branch item");
-        (self.build_key(None), &self.trie.arena()[value])
+    /// The node at `key` is not in memory
+    ///
+    /// Returns its hash index, the iteration resumes from it once it is loaded.
+    fn missing(&mut self, key: Index) -> Result<Option<(Vec<u8>, usize)>, usize> {
+        match key {
+            Index::Hash(hash) => {
+                self.start = Some(key);
+                Err(hash)
+            }
+            Index::Memory(_) => {
+                error!("memory node {:?} doesn't exist, stopping iteration", key);
+                self.stack.clear();
+                Ok(None)
+            }
+        }
     }
 
-    fn leaf_item(&mut self, leaf: &'a Leaf) -> (Cow<'a, [u8]>, &'a [u8]) {
-        debug!("getting leaf item");
-        (self.build_key(Some(leaf)), &self.trie.arena()[leaf.value])
+    /// The next (key, value index), or the hash index of a node to load first
+    fn next(&mut self, db: &Db, arena: &Arena) -> Result<Option<(Vec<u8>, usize)>, usize> {
+        if self.reverse {
+            return self.next_reverse(db, arena);
+        }
+        let mut key = match self.start.take() {
+            Some(start) => start,
+            None => match self.next_child(db) {
+                Some(key) => key,
+                None => return Ok(None),
+            },
+        };
+
+        loop {
+            debug!("iter {:?}", key);
+            let node = match db.get(&key) {
+                Some(node) => node,
+                None => return self.missing(key),
+            };
+            match node {
+                Node::Leaf(ref leaf) => {
+                    return Ok(Some((self.build_key(db, arena, Some(leaf)), leaf.value)));
+                }
+                Node::Extension(ref extension) => {
+                    self.stack.push(Frame::Extension(key));
+                    key = extension.key;
+                }
+                Node::Branch(ref branch) => {
+                    self.stack.push(Frame::Branch(key, None));
+                    if let Some(v) = branch.value {
+                        return Ok(Some((self.build_key(db, arena, None), v)));
+                    }
+                    key = match self.next_child(db) {
+                        Some(key) => key,
+                        None => return Ok(None),
+                    };
+                }
+                Node::Empty => {
+                    warn!("found empty node");
+                    return Ok(None);
+                }
+            }
+        }
     }
-}
 
-impl<'a> Iterator for DFSIter<'a> {
-    type Item = (Cow<'a, [u8]>, &'a [u8]);
-    fn next(&mut self) -> Option<Self::Item> {
-        let mut key = if self.root {
-            self.root = false;
-            self.trie.db().root_index()
-        } else {
-            // search up the stack for the next branch key
-            loop {
-                match self.stack.pop()? {
-                    NodeIter::Branch(branch, n) => {
-                        let start = n.map_or(0, |n| n as usize + 1);
-                        if let Some(p) = branch.keys.iter().skip(start).position(|k| k.is_some()) {
-                            self.stack
-                                .push(NodeIter::Branch(branch, Some((start + p) as u8)));
-                            break branch.keys[start + p]?;
-                        }
+    /// Searches up the stack for the next branch key
+    fn next_child(&mut self, db: &Db) -> Option<Index> {
+        loop {
+            match self.stack.pop()? {
+                Frame::Branch(index, n) => {
+                    let branch = branch(db, &index)?;
+                    let start = n.map_or(0, |n| n as usize + 1);
+                    if let Some(p) = branch.keys.iter().skip(start).position(|k| k.is_some()) {
+                        self.stack
+                            .push(Frame::Branch(index, Some((start + p) as u8)));
+                        return branch.keys[start + p];
                     }
-                    NodeIter::Extension(_) => (),
                 }
+                Frame::Extension(_) => (),
             }
+        }
+    }
+
+    fn next_reverse(&mut self, db: &Db, arena: &Arena) -> Result<Option<(Vec<u8>, usize)>, usize> {
+        let mut key = match self.start.take() {
+            Some(start) => start,
+            None => match self.previous(db) {
+                Previous::Child(key) => key,
+                Previous::Value(v) => return Ok(Some((self.build_key(db, arena, None), v))),
+                Previous::Done => return Ok(None),
+            },
         };
 
         loop {
-            debug!("iter {:?}", key);
-            match self.trie.db().get(&key)? {
-                Node::Leaf(ref leaf) => return Some(self.leaf_item(leaf)),
+            debug!("iter reverse {:?}", key);
+            let node = match db.get(&key) {
+                Some(node) => node,
+                None => return self.missing(key),
+            };
+            match node {
+                Node::Leaf(ref leaf) => {
+                    return Ok(Some((self.build_key(db, arena, Some(leaf)), leaf.value)));
+                }
                 Node::Extension(ref extension) => {
-                    self.stack.push(NodeIter::Extension(&extension));
+                    self.stack.push(Frame::Extension(key));
                     key = extension.key;
                 }
-                Node::Branch(ref branch) => {
-                    self.stack.push(NodeIter::Branch(branch, None));
-                    return if let Some(v) = branch.value {
-                        Some(self.branch_item(v))
-                    } else {
-                        self.next()
+                Node::Branch(_) => {
+                    // children first, the branch value is yielded once they are all done
+                    self.stack.push(Frame::Branch(key, None));
+                    key = match self.previous(db) {
+                        Previous::Child(key) => key,
+                        Previous::Value(v) => {
+                            return Ok(Some((self.build_key(db, arena, None), v)));
+                        }
+                        Previous::Done => return Ok(None),
                     };
                 }
                 Node::Empty => {
                     warn!("found empty node");
-                    return None;
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    /// Searches up the stack for the previous branch key or the branch value
+    fn previous(&mut self, db: &Db) -> Previous {
+        loop {
+            match self.stack.pop() {
+                Some(Frame::Branch(index, n)) => {
+                    let branch = match branch(db, &index) {
+                        Some(branch) => branch,
+                        None => return Previous::Done,
+                    };
+                    let end = n.map_or(16, |n| n as usize);
+                    if let Some(p) = branch.keys[..end].iter().rposition(|k| k.is_some()) {
+                        self.stack.push(Frame::Branch(index, Some(p as u8)));
+                        return branch.keys[p].map_or(Previous::Done, Previous::Child);
+                    }
+                    if let Some(v) = branch.value {
+                        return Previous::Value(v);
+                    }
+                }
+                Some(Frame::Extension(_)) => (),
+                None => return Previous::Done,
+            }
+        }
+    }
+}
+
+impl<'a> DFSIter<'a> {
+    pub(crate) fn new<S: NodeStore, H: Hasher>(trie: &'a Trie<S, H>) -> Self {
+        DFSIter {
+            cursor: Cursor::new(trie.db().root_index()),
+            db: trie.db(),
+            arena: trie.arena(),
+        }
+    }
+
+    /// Creates an iterator over the items whose key starts with `prefix`
+    pub(crate) fn with_prefix<S: NodeStore, H: Hasher>(
+        trie: &'a Trie<S, H>,
+        prefix: &[u8],
+    ) -> Self {
+        let (db, arena) = (trie.db(), trie.arena());
+        DFSIter {
+            cursor: Cursor::with_prefix(db.root_index(), db, arena, prefix),
+            db,
+            arena,
+        }
+    }
+
+    /// Returns an iterator over the same items, in descending key order
+    ///
+    /// The iteration restarts from the first (last) item
+    pub fn reversed(mut self) -> Self {
+        self.cursor.reversed();
+        self
+    }
+
+    /// Moves the iterator to the first item whose key is greater or equal to `key`
+    ///
+    /// When iterating in reverse, moves to the first item lower or equal to `key`
+    pub fn seek<K: AsRef<[u8]>>(&mut self, key: K) {
+        self.cursor.seek(self.db, self.arena, key.as_ref());
+    }
+}
+
+impl<'a> Iterator for DFSIter<'a> {
+    type Item = (Cow<'a, [u8]>, &'a [u8]);
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.cursor.next(self.db, self.arena) {
+            Ok(item) => item.map(|(key, value)| (Cow::Owned(key), &self.arena[value])),
+            Err(hash) => {
+                error!("node {} is not loaded, stopping iteration", hash);
+                self.cursor.stack.clear();
+                self.cursor.start = None;
+                None
+            }
+        }
+    }
+}
+
+/// The bounds of a range iteration
+struct Bounds {
+    /// An excluded start key, skipped if it is the first item
+    excluded: Option<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    /// The end bound has been passed
+    done: bool,
+}
+
+impl Bounds {
+    /// Returns the bounds, and the key to seek to, if any
+    fn new<'k>(start: Bound<&'k [u8]>, end: Bound<&[u8]>) -> (Bounds, Option<&'k [u8]>) {
+        let (excluded, seek) = match start {
+            Bound::Included(key) => (None, Some(key)),
+            Bound::Excluded(key) => (Some(key.to_vec()), Some(key)),
+            Bound::Unbounded => (None, None),
+        };
+        let end = match end {
+            Bound::Included(key) => Bound::Included(key.to_vec()),
+            Bound::Excluded(key) => Bound::Excluded(key.to_vec()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let bounds = Bounds {
+            excluded,
+            end,
+            done: false,
+        };
+        (bounds, seek)
+    }
+
+    fn unbounded() -> Self {
+        Bounds::new(Bound::Unbounded, Bound::Unbounded).0
+    }
+
+    /// Whether the item at `key` is yielded, `None` once past the end
+    fn accept(&mut self, key: &[u8]) -> Option<bool> {
+        if let Some(excluded) = self.excluded.take() {
+            if *key == *excluded {
+                return Some(false);
+            }
+        }
+        let in_range = match self.end {
+            Bound::Included(ref end) => *key <= **end,
+            Bound::Excluded(ref end) => *key < **end,
+            Bound::Unbounded => true,
+        };
+        if !in_range {
+            self.done = true;
+            return None;
+        }
+        Some(true)
+    }
+}
+
+/// An iterator over the items whose keys are within a range, in lexicographic order
+pub struct RangeIter<'a> {
+    iter: DFSIter<'a>,
+    bounds: Bounds,
+}
+
+impl<'a> RangeIter<'a> {
+    pub(crate) fn new<S: NodeStore, H: Hasher>(
+        trie: &'a Trie<S, H>,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> Self {
+        let mut iter = DFSIter::new(trie);
+        let (bounds, seek) = Bounds::new(start, end);
+        if let Some(key) = seek {
+            iter.seek(key);
+        }
+        RangeIter { iter, bounds }
+    }
+}
+
+impl<'a> Iterator for RangeIter<'a> {
+    type Item = (Cow<'a, [u8]>, &'a [u8]);
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.bounds.done {
+            let (key, value) = self.iter.next()?;
+            if self.bounds.accept(&key)? {
+                return Some((key, value));
+            }
+        }
+        None
+    }
+}
+
+/// An iterator loading the nodes from the store as it descends, see `Trie::iter_loading`
+///
+/// Items are owned since the trie changes while loading nodes. The iteration stops after
+/// a node fails to load.
+pub struct LoadingIter<'a, S: NodeStore, H: Hasher> {
+    cursor: Cursor,
+    trie: &'a mut Trie<S, H>,
+    bounds: Bounds,
+}
+
+impl<'a, S: NodeStore, H: Hasher> LoadingIter<'a, S, H> {
+    pub(crate) fn new(trie: &'a mut Trie<S, H>, reverse: bool) -> Self {
+        let mut cursor = Cursor::new(trie.db().root_index());
+        cursor.reverse = reverse;
+        LoadingIter {
+            cursor,
+            trie,
+            bounds: Bounds::unbounded(),
+        }
+    }
+
+    /// Creates an iterator over the items whose key starts with `prefix`
+    ///
+    /// Only the nodes on the path of the prefix are loaded beforehand.
+    pub(crate) fn with_prefix(trie: &'a mut Trie<S, H>, prefix: &[u8]) -> Result<Self, TrieError> {
+        trie.load_path(prefix)?;
+        let (db, arena) = (trie.db(), trie.arena());
+        let cursor = Cursor::with_prefix(db.root_index(), db, arena, prefix);
+        Ok(LoadingIter {
+            cursor,
+            trie,
+            bounds: Bounds::unbounded(),
+        })
+    }
+
+    /// Creates an iterator over the items within `start` and `end`
+    ///
+    /// Only the nodes on the path of `start` are loaded beforehand.
+    pub(crate) fn range(
+        trie: &'a mut Trie<S, H>,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> Result<Self, TrieError> {
+        let (bounds, seek) = Bounds::new(start, end);
+        let mut cursor = Cursor::new(trie.db().root_index());
+        if let Some(key) = seek {
+            trie.load_path(key)?;
+            cursor.seek(trie.db(), trie.arena(), key);
+        }
+        Ok(LoadingIter {
+            cursor,
+            trie,
+            bounds,
+        })
+    }
+}
+
+impl<'a, S: NodeStore, H: Hasher> Iterator for LoadingIter<'a, S, H> {
+    type Item = Result<(Vec<u8>, Vec<u8>), TrieError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.bounds.done {
+            match self.cursor.next(self.trie.db(), self.trie.arena()) {
+                Ok(Some((key, value))) => {
+                    if self.bounds.accept(&key)? {
+                        return Some(Ok((key, self.trie.arena()[value].to_vec())));
+                    }
+                }
+                Ok(None) => return None,
+                Err(hash) => {
+                    if let Err(e) = self.trie.load(Index::Hash(hash)) {
+                        self.bounds.done = true;
+                        return Some(Err(e));
+                    }
                 }
             }
         }
+        None
     }
 }
@@ -1,3 +1,13 @@
+// lints raised by code which predates them
+#![allow(
+    clippy::borrow_deref_ref,
+    clippy::needless_borrow,
+    clippy::new_without_default,
+    clippy::redundant_static_lifetimes,
+    clippy::useless_conversion
+)]
+#![cfg_attr(test, allow(deprecated, clippy::needless_borrows_for_generic_args))]
+
 #[macro_use]
 extern crate log;
 extern crate keccak_hash;
@@ -11,8 +21,22 @@ extern crate keccak_hasher;
 extern crate triehash;
 
 pub mod arena;
+pub mod builder;
 pub mod db;
+pub mod diff;
+pub mod eip1186;
+pub mod error;
+pub mod file;
+pub mod hasher;
 pub mod iter;
 pub mod nibbles;
 pub mod node;
+pub mod ordered;
+pub mod proof;
+pub mod refcount;
+pub mod secure;
+pub mod store;
 pub mod trie;
+
+#[cfg(test)]
+mod testutil;
@@ -1,4 +1,5 @@
 use arena::Arena;
+use error::TrieError;
 use std::ops::Index;
 
 #[derive(Debug, Default, PartialEq, Clone)]
@@ -24,6 +25,11 @@ impl Nibble {
         self.end - self.start
     }
 
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.end == self.start
+    }
+
     pub fn iter<'a, A: Index<usize, Output = [u8]>>(
         &'a self,
         arena: &'a A,
@@ -36,7 +42,7 @@ impl Nibble {
     }
 
     pub fn pop_front<A: Index<usize, Output = [u8]>>(&self, arena: &A) -> Option<(u8, Nibble)> {
-        if self.len() == 0 {
+        if self.is_empty() {
             return None;
         }
         let first = arena[self.data][self.start as usize / 2];
@@ -80,6 +86,25 @@ impl Nibble {
             .all(|(u, v)| u == v)
     }
 
+    /// Creates a new nibble out of a slice of half bytes
+    pub fn from_nibbles(nibbles: &[u8], arena: &mut Arena) -> Nibble {
+        let start = nibbles.len() as u32 % 2;
+        let mut data = Vec::with_capacity(nibbles.len() / 2 + 1);
+        if start == 1 {
+            data.push(nibbles[0]);
+        }
+        data.extend(
+            nibbles[start as usize..]
+                .chunks(2)
+                .map(|w| w[0] << 4 | w[1]),
+        );
+        Nibble {
+            data: arena.push(&data),
+            start,
+            end: start + nibbles.len() as u32,
+        }
+    }
+
     pub fn copy<A>(&self, self_arena: &A, new_arena: &mut Arena) -> Nibble
     where
         A: Index<usize, Output = [u8]>,
@@ -131,47 +156,25 @@ impl Nibble {
     }
 
     /// Decode a slice into a nibble, return true if it is a leaf
-    pub fn from_encoded<A>(data: usize, arena: &A) -> (bool, Self)
+    pub fn from_encoded<A>(data: usize, arena: &A) -> Result<(bool, Self), TrieError>
     where
         A: Index<usize, Output = [u8]>,
     {
         let bytes = &arena[data];
-        assert!(!bytes.is_empty(), "Cannot decode empty slice");
-        match bytes[0] & 0xF0 {
-            0x00 => (
-                false,
-                Nibble {
-                    data,
-                    start: 2,
-                    end: bytes.len() as u32 * 2,
-                },
-            ),
-            0x10 => (
-                false,
-                Nibble {
-                    data,
-                    start: 1,
-                    end: bytes.len() as u32 * 2,
-                },
-            ),
-            0x20 => (
-                true,
-                Nibble {
-                    data,
-                    start: 2,
-                    end: bytes.len() as u32 * 2,
-                },
-            ),
-            0x30 => (
-                true,
-                Nibble {
-                    data,
-                    start: 1,
-                    end: bytes.len() as u32 * 2,
-                },
-            ),
-            s => panic!("Cannot decode slice starting with {:X}", s),
-        }
+        let (is_leaf, start) = match bytes.first().map(|b| b & 0xF0) {
+            None => return Err(TrieError::Corrupted("cannot decode empty nibble")),
+            Some(0x00) => (false, 2),
+            Some(0x10) => (false, 1),
+            Some(0x20) => (true, 2),
+            Some(0x30) => (true, 1),
+            Some(s) => return Err(TrieError::InvalidNibblePrefix(s)),
+        };
+        let nibble = Nibble {
+            data,
+            start,
+            end: bytes.len() as u32 * 2,
+        };
+        Ok((is_leaf, nibble))
     }
 }
 
@@ -242,6 +245,20 @@ mod test {
         assert_eq!(&n.encoded(true, &arena), &[0x32, 0x34]);
     }
 
+    #[test]
+    fn from_nibbles() {
+        let mut arena = Arena::new();
+        let n = Nibble::from_nibbles(&[1, 2, 3, 4, 5], &mut arena);
+        assert_eq!(n.iter(&arena).collect::<Vec<_>>(), vec![1, 2, 3, 4, 5]);
+        assert_eq!(&n.encoded(true, &arena), &[0x31, 0x23, 0x45]);
+        let n = Nibble::from_nibbles(&[1, 2, 3, 4], &mut arena);
+        assert_eq!(n.iter(&arena).collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+        assert_eq!(&n.encoded(false, &arena), &[0x00, 0x12, 0x34]);
+        let n = Nibble::from_nibbles(&[], &mut arena);
+        assert!(n.is_empty());
+        assert_eq!(&n.encoded(true, &arena), &[0x20]);
+    }
+
     #[test]
     fn iter_nibble() {
         let mut arena = Arena::new();
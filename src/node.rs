@@ -1,11 +1,12 @@
 use arena::Arena;
 use db::Index;
-use keccak_hash::H256;
+use error::TrieError;
+use hasher::Hasher;
 use nibbles::Nibble;
-use rlp::{DecoderError, Prototype, Rlp, RlpStream};
+use rlp::{DecoderError, Prototype, Rlp, RlpStream, NULL_RLP};
 
 /// A trie `Node`
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Node {
     Empty,
     Branch(Box<Branch>),
@@ -14,36 +15,29 @@ pub enum Node {
 }
 
 impl Node {
-    pub fn try_from_encoded(data: &[u8], arena: &mut Arena) -> Option<Self> {
-        match Node::from_encoded_res(&data, arena) {
-            Ok(n) => Some(n),
-            Err(e) => {
-                error!("Error decoding rlp node {}", e);
-                None
-            }
-        }
-    }
-
-    fn from_encoded_res(data: &[u8], arena: &mut Arena) -> Result<Self, DecoderError> {
+    /// Decode a rlp encoded node
+    pub fn try_from_encoded(data: &[u8], arena: &mut Arena) -> Result<Self, TrieError> {
         let r = Rlp::new(data);
         match r.prototype()? {
             Prototype::List(2) => {
                 let nibble = arena.push(r.at(0)?.data()?);
-                let value = arena.push(r.at(1)?.data()?);
-                match Nibble::from_encoded(nibble, arena) {
-                    (true, nibble) => Ok(Node::Leaf(Leaf { nibble, value })),
+                match Nibble::from_encoded(nibble, arena)? {
+                    (true, nibble) => {
+                        let value = arena.push(r.at(1)?.data()?);
+                        Ok(Node::Leaf(Leaf { nibble, value }))
+                    }
                     (false, nibble) => Ok(Node::Extension(Extension {
                         nibble,
-                        key: Index::Hash(value),
+                        key: Index::Hash(push_child(&r.at(1)?, arena)?),
                     })),
                 }
             }
             Prototype::List(17) => {
                 let mut branch = Branch::default();
                 for i in 0..16 {
-                    let key = r.at(i)?.as_raw();
+                    let key = r.at(i)?;
                     if !key.is_empty() {
-                        branch.keys[i] = Some(Index::Hash(arena.push(key)));
+                        branch.keys[i] = Some(Index::Hash(push_child(&key, arena)?));
                     }
                 }
                 let value = r.at(16)?;
@@ -53,12 +47,40 @@ impl Node {
                 Ok(Node::Branch(Box::new(branch)))
             }
             Prototype::Data(0) => Ok(Node::Empty),
-            _ => Err(DecoderError::Custom("Rlp is not valid.")),
+            _ => Err(DecoderError::Custom("Rlp is not valid.").into()),
+        }
+    }
+
+    /// RLP encode the node without storing it
+    ///
+    /// Returns `None` if the node refers to Memory nodes
+    pub fn encoded_vec<H, A>(&self, arena: &A) -> Option<Vec<u8>>
+    where
+        H: Hasher,
+        A: ::std::ops::Index<usize, Output = [u8]>,
+    {
+        match self {
+            Node::Leaf(leaf) => Some(leaf.stream(arena).out()),
+            Node::Branch(branch) => Some(branch.stream::<H, _>(arena).out()),
+            Node::Extension(extension) => extension.stream::<H, _>(arena).map(|s| s.out()),
+            Node::Empty => Some(NULL_RLP.to_vec()),
         }
     }
 }
 
-#[derive(Debug, Default)]
+/// Push a child reference into the arena
+///
+/// A child is either the hash of its encoding or, if it is small enough,
+/// the encoding itself (inlined)
+fn push_child(r: &Rlp, arena: &mut Arena) -> Result<usize, DecoderError> {
+    if r.is_list() {
+        Ok(arena.push(r.as_raw()))
+    } else {
+        Ok(arena.push(r.data()?))
+    }
+}
+
+#[derive(Debug, Default, Clone)]
 pub struct Branch {
     pub keys: [Option<Index>; 16],
     pub value: Option<usize>,
@@ -68,13 +90,22 @@ impl Branch {
     /// RLP encode the branch
     ///
     /// Ignores Memory nodes
-    pub fn encoded(&mut self, arena: &mut Arena) -> usize {
+    pub fn encoded<H: Hasher>(&mut self, arena: &mut Arena) -> usize {
+        let stream = self.stream::<H, _>(arena);
+        arena.push(&stream.drain())
+    }
+
+    fn stream<H, A>(&self, arena: &A) -> RlpStream
+    where
+        H: Hasher,
+        A: ::std::ops::Index<usize, Output = [u8]>,
+    {
         let mut stream = RlpStream::new_list(17);
         for k in &self.keys {
             match k {
                 Some(Index::Hash(i)) => {
                     let data = &arena[*i];
-                    if data.len() < H256::len() {
+                    if data.len() < H::LENGTH {
                         // inlined
                         stream.append_raw(&data, 1);
                     } else {
@@ -94,7 +125,7 @@ impl Branch {
                 stream.append(&&arena[*i]);
             }
         }
-        arena.push(&stream.drain())
+        stream
     }
 }
 
@@ -115,13 +146,18 @@ impl Leaf {
     ///
     /// Always work
     pub fn encoded(&self, arena: &mut Arena) -> usize {
+        let stream = self.stream(arena);
+        arena.push(&stream.drain())
+    }
+
+    fn stream<A: ::std::ops::Index<usize, Output = [u8]>>(&self, arena: &A) -> RlpStream {
         let mut stream = RlpStream::new();
         let buffer = self.nibble.encoded(true, arena);
         stream
             .begin_list(2)
             .append(&buffer)
             .append(&&arena[self.value]);
-        arena.push(&stream.drain())
+        stream
     }
 }
 
@@ -133,12 +169,33 @@ pub struct Extension {
 
 impl Extension {
     /// RLP encode the extension
-    pub fn encoded_or_empty(&mut self, arena: &mut Arena, empty: usize) -> usize {
+    ///
+    /// Returns `None` if the child is a Memory node
+    pub fn encoded<H: Hasher>(&self, arena: &mut Arena) -> Option<usize> {
+        let stream = self.stream::<H, _>(arena)?;
+        Some(arena.push(&stream.drain()))
+    }
+
+    /// RLP encode the extension, falling back to the `empty` node
+    pub fn encoded_or_empty<H: Hasher>(&mut self, arena: &mut Arena, empty: usize) -> usize {
+        match self.encoded::<H>(arena) {
+            Some(encoded) => encoded,
+            None => {
+                warn!("hashing memory extension");
+                empty
+            }
+        }
+    }
+
+    fn stream<H, A>(&self, arena: &A) -> Option<RlpStream>
+    where
+        H: Hasher,
+        A: ::std::ops::Index<usize, Output = [u8]>,
+    {
         let key = if let Index::Hash(i) = self.key {
             i
         } else {
-            warn!("hashing memory extension");
-            return empty;
+            return None;
         };
 
         let mut stream = RlpStream::new_list(2);
@@ -146,13 +203,13 @@ impl Extension {
 
         {
             let key = &arena[key];
-            if key.len() < H256::len() {
+            if key.len() < H::LENGTH {
                 // inline already encoded data
                 stream.append_raw(key, 1);
             } else {
                 stream.append(&key);
             }
         }
-        arena.push(&stream.drain())
+        Some(stream)
     }
 }
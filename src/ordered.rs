@@ -0,0 +1,51 @@
+//! Roots of ordered lists, as ethereum transactions, receipts or withdrawals roots
+
+use rlp;
+use trie::Trie;
+
+/// Computes the root of a trie whose keys are the rlp encoded indexes of `items`
+///
+/// Equivalent to `triehash::ordered_trie_root` with keccak.
+pub fn ordered_trie_root<I, V>(items: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = V>,
+    V: AsRef<[u8]>,
+{
+    // The trie is backed by the `()` store and built from scratch, so no node is ever missing
+    // (none was evicted or left unloaded), no checkpoint is taken and committing into `()`
+    // always succeeds: neither call can fail.
+    let mut trie = Trie::new();
+    for (i, item) in items.into_iter().enumerate() {
+        trie.insert(rlp::encode(&i), item)
+            .expect("in memory trie has no missing node");
+    }
+    trie.root()
+        .expect("in memory trie commit cannot fail")
+        .to_vec()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use keccak_hasher::KeccakHasher;
+    use triehash;
+
+    fn check(n: usize) {
+        let items = (0..n)
+            .map(|i| vec![(i % 251) as u8; 1 + i % 70])
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ordered_trie_root(&items),
+            &*triehash::ordered_trie_root::<KeccakHasher, _, _>(&items),
+            "{} items",
+            n
+        );
+    }
+
+    #[test]
+    fn ordered_root() {
+        for n in &[0, 1, 2, 16, 127, 128, 129, 300, 1000] {
+            check(*n);
+        }
+    }
+}
@@ -0,0 +1,465 @@
+use arena::Arena;
+use db::Index;
+use error::TrieError;
+use hasher::{Hasher, Keccak};
+use nibbles::Nibble;
+use node::Node;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use trie::Trie;
+
+/// An error occuring while verifying a merkle proof
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProofError {
+    /// A proof node is not a valid rlp encoded node
+    InvalidNode,
+    /// A proof node hash doesn't match the hash referenced by its parent (or the root)
+    HashMismatch,
+    /// The proof ends before the key path could be resolved
+    IncompleteProof,
+    /// Range proof items are not sorted or not within the range
+    InvalidRange,
+}
+
+impl fmt::Display for ProofError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProofError::InvalidNode => write!(f, "proof node cannot be decoded"),
+            ProofError::HashMismatch => write!(f, "proof node hash doesn't match its reference"),
+            ProofError::IncompleteProof => write!(f, "proof is missing nodes"),
+            ProofError::InvalidRange => write!(f, "range items are not sorted within the range"),
+        }
+    }
+}
+
+impl Error for ProofError {}
+
+impl From<TrieError> for ProofError {
+    fn from(e: TrieError) -> Self {
+        match e {
+            TrieError::MissingNode(_) => ProofError::IncompleteProof,
+            _ => ProofError::InvalidNode,
+        }
+    }
+}
+
+/// Consecutive items of a trie along with the nodes proving them
+#[derive(Debug, Clone, PartialEq)]
+pub struct RangeProof {
+    /// The (key, value) items, sorted by key
+    pub items: Vec<(Vec<u8>, Vec<u8>)>,
+    /// The rlp encoded nodes proving the first and last keys of the range
+    pub proof: Vec<Vec<u8>>,
+}
+
+/// The proof nodes by their hash
+pub(crate) fn proof_store<H: Hasher>(proof: &[Vec<u8>]) -> HashMap<Vec<u8>, Vec<u8>> {
+    proof
+        .iter()
+        .map(|node| (H::hash(node).as_ref().to_vec(), node.clone()))
+        .collect()
+}
+
+/// Verify a merkle proof as returned by `Trie::get_proof`
+///
+/// Returns the value proven to be at `key` or `None` if the proof shows there is no such key.
+pub fn verify_proof<K: AsRef<[u8]>>(
+    root: &[u8],
+    key: K,
+    proof: &[Vec<u8>],
+) -> Result<Option<Vec<u8>>, ProofError> {
+    verify_proof_with_hasher::<Keccak, _>(root, key, proof)
+}
+
+/// Verify a merkle proof of a trie hashing its nodes with `H`, see `verify_proof`
+pub fn verify_proof_with_hasher<H: Hasher, K: AsRef<[u8]>>(
+    root: &[u8],
+    key: K,
+    proof: &[Vec<u8>],
+) -> Result<Option<Vec<u8>>, ProofError> {
+    let mut arena = Arena::new();
+    let key = key.as_ref();
+    let mut path = Nibble::new(key, &mut arena);
+    let mut nodes = proof.iter();
+
+    // the root is always hashed, other nodes are only if their encoding is long enough
+    let mut reference = arena.push(root);
+    let mut is_root = true;
+
+    loop {
+        let node = if is_root || arena[reference].len() >= H::LENGTH {
+            let encoded = nodes.next().ok_or(ProofError::IncompleteProof)?;
+            if H::hash(encoded).as_ref() != &arena[reference] {
+                return Err(ProofError::HashMismatch);
+            }
+            Node::try_from_encoded(encoded, &mut arena)
+        } else {
+            let encoded = arena[reference].to_vec();
+            Node::try_from_encoded(&encoded, &mut arena)
+        };
+        is_root = false;
+
+        match node.map_err(|_| ProofError::InvalidNode)? {
+            Node::Empty => return Ok(None),
+            Node::Leaf(leaf) => {
+                return if leaf.nibble.eq(&path, &arena, &arena) {
+                    Ok(Some(arena[leaf.value].to_vec()))
+                } else {
+                    Ok(None)
+                };
+            }
+            Node::Extension(extension) => {
+                let (left, right) = path.split_at(extension.nibble.len());
+                if !extension.nibble.eq(&left, &arena, &arena) {
+                    return Ok(None);
+                }
+                path = right.unwrap_or_default();
+                reference = match extension.key {
+                    Index::Hash(i) => i,
+                    Index::Memory(_) => return Err(ProofError::InvalidNode),
+                };
+            }
+            Node::Branch(branch) => match path.pop_front(&arena) {
+                Some((u, n)) => match branch.keys[u as usize] {
+                    Some(Index::Hash(i)) => {
+                        reference = i;
+                        path = n;
+                    }
+                    Some(Index::Memory(_)) => return Err(ProofError::InvalidNode),
+                    None => return Ok(None),
+                },
+                None => return Ok(branch.value.map(|v| arena[v].to_vec())),
+            },
+        }
+    }
+}
+
+/// Verify a multi-key proof as returned by `Trie::get_multiproof`
+///
+/// Returns the values proven to be at `keys`, in the same order, or `None` for keys
+/// proven to be absent.
+pub fn verify_multiproof<K: AsRef<[u8]>>(
+    root: &[u8],
+    keys: &[K],
+    proof: &[Vec<u8>],
+) -> Result<Vec<Option<Vec<u8>>>, ProofError> {
+    verify_multiproof_with_hasher::<Keccak, _>(root, keys, proof)
+}
+
+/// Verify a multi-key proof of a trie hashing its nodes with `H`, see `verify_multiproof`
+pub fn verify_multiproof_with_hasher<H: Hasher, K: AsRef<[u8]>>(
+    root: &[u8],
+    keys: &[K],
+    proof: &[Vec<u8>],
+) -> Result<Vec<Option<Vec<u8>>>, ProofError> {
+    let mut trie = Trie::<_, H>::from_root_with_hasher(root, proof_store::<H>(proof));
+    keys.iter()
+        .map(|key| Ok(trie.get_loading(key)?.map(|v| v.to_vec())))
+        .collect()
+}
+
+/// Verify a range proof as returned by `Trie::get_range_proof`
+///
+/// Checks that `range.items` are all the items of the trie with a key between `start` and
+/// the last item key (or `end` if there is no item). A partial trie is rebuilt out of the
+/// proof nodes, everything within the range is replaced by the items and the resulting
+/// root must match `root`.
+pub fn verify_range_proof<K: AsRef<[u8]>>(
+    root: &[u8],
+    start: K,
+    end: K,
+    range: &RangeProof,
+) -> Result<(), ProofError> {
+    verify_range_proof_with_hasher::<Keccak, _>(root, start, end, range)
+}
+
+/// Verify a range proof of a trie hashing its nodes with `H`, see `verify_range_proof`
+pub fn verify_range_proof_with_hasher<H: Hasher, K: AsRef<[u8]>>(
+    root: &[u8],
+    start: K,
+    end: K,
+    range: &RangeProof,
+) -> Result<(), ProofError> {
+    let (start, end) = (start.as_ref(), end.as_ref());
+    let items = &range.items;
+    if start > end
+        || items.windows(2).any(|w| w[0].0 >= w[1].0)
+        || items.first().map_or(false, |(k, _)| &**k < start)
+        || items.last().map_or(false, |(k, _)| &**k > end)
+    {
+        return Err(ProofError::InvalidRange);
+    }
+    let right = items.last().map_or(end, |(k, _)| &**k);
+
+    let store = proof_store::<H>(&range.proof);
+    let mut trie = Trie::<_, H>::from_root_with_hasher(root, store);
+    trie.unset_range(start, right)?;
+    for (k, v) in items {
+        trie.insert(k, v)?;
+    }
+    if trie.root()? != root {
+        return Err(ProofError::HashMismatch);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use keccak_hash::keccak;
+    use testutil::{sample, Short};
+
+    fn trie(items: &[(Vec<u8>, Vec<u8>)]) -> Trie {
+        let mut t = Trie::new();
+        for (k, v) in items {
+            t.insert(k, v).unwrap();
+        }
+        t
+    }
+
+    #[test]
+    fn verify() {
+        let items = sample(50);
+        let mut t = trie(&items);
+        let root = t.root().unwrap().to_vec();
+        for (k, v) in &items {
+            let proof = t.get_proof(k).unwrap();
+            assert_eq!(verify_proof(&root, k, &proof), Ok(Some(v.clone())));
+        }
+        let proof = t.get_proof([0x01, 0x02, 0x03]).unwrap();
+        assert_eq!(verify_proof(&root, [0x01, 0x02, 0x03], &proof), Ok(None));
+        let proof = t.get_proof([0x01]).unwrap();
+        assert_eq!(verify_proof(&root, [0x01], &proof), Ok(None));
+    }
+
+    #[test]
+    fn verify_inlined() {
+        // small values make nodes shorter than a hash, they are inlined into their parent
+        let items = vec![
+            (vec![0x01, 0x23], vec![0x01]),
+            (vec![0x01, 0x24], vec![0x02]),
+            (vec![0x01], vec![0x03]),
+            (vec![0xf1, 0x23], vec![0x04]),
+        ];
+        let mut t = trie(&items);
+        let root = t.root().unwrap().to_vec();
+        for (k, v) in &items {
+            let proof = t.get_proof(k).unwrap();
+            assert_eq!(verify_proof(&root, k, &proof), Ok(Some(v.clone())));
+        }
+        let proof = t.get_proof([0x01, 0x25]).unwrap();
+        assert_eq!(verify_proof(&root, [0x01, 0x25], &proof), Ok(None));
+    }
+
+    #[test]
+    fn verify_empty() {
+        let mut t = Trie::new();
+        let root = t.root().unwrap().to_vec();
+        let proof = t.get_proof([0x01]).unwrap();
+        assert_eq!(verify_proof(&root, [0x01], &proof), Ok(None));
+    }
+
+    #[test]
+    fn verify_invalid() {
+        // values long enough for every node to be hashed
+        let items = sample(20)
+            .into_iter()
+            .map(|(k, v)| (k, vec![v[0]; 33]))
+            .collect::<Vec<_>>();
+        let mut t = trie(&items);
+        let root = t.root().unwrap().to_vec();
+        let key = &items[3].0;
+        let mut proof = t.get_proof(key).unwrap();
+
+        assert_eq!(
+            verify_proof(&root, key, &proof[..proof.len() - 1]),
+            Err(ProofError::IncompleteProof)
+        );
+
+        let last = proof.last_mut().unwrap();
+        let len = last.len();
+        last[len - 1] ^= 1;
+        assert_eq!(
+            verify_proof(&root, key, &proof),
+            Err(ProofError::HashMismatch)
+        );
+
+        assert_eq!(
+            verify_proof(&keccak([0xff]), key, &[vec![0xff]]),
+            Err(ProofError::InvalidNode)
+        );
+    }
+
+    #[test]
+    fn verify_range() {
+        let mut items = sample(100);
+        let mut t = trie(&items);
+        let root = t.root().unwrap().to_vec();
+        items.sort();
+
+        let ranges: &[(&[u8], &[u8], usize)] = &[
+            (&[], &[0xff, 0xff], 1000),
+            (&[], &[0xff, 0xff], 10),
+            (&[0x12], &[0x80], 1000),
+            (&[0x12, 0x00], &[0x80], 7),
+            (&[0x13], &[0x14], 1000),
+            (&[0xff], &[0xff, 0xff], 1000),
+        ];
+        for &(start, end, limit) in ranges {
+            let range = t.get_range_proof(start, end, limit).unwrap();
+            let expected = items
+                .iter()
+                .filter(|(k, _)| &**k >= start && &**k <= end)
+                .take(limit)
+                .cloned()
+                .collect::<Vec<_>>();
+            assert_eq!(range.items, expected, "range {:?}..={:?}", start, end);
+            assert_eq!(verify_range_proof(&root, start, end, &range), Ok(()));
+        }
+    }
+
+    #[test]
+    fn range_proof_lazy() {
+        let items = sample(100);
+        let mut store = HashMap::new();
+        let root = {
+            let mut t = Trie::with_store(&mut store);
+            for (k, v) in &items {
+                t.insert(k, v).unwrap();
+            }
+            t.root().unwrap().to_vec()
+        };
+
+        // only the nodes up to the limit are loaded
+        let (start, end) = (&[][..], &[0xff, 0xff][..]);
+        let mut t = Trie::from_root(&root, store);
+        let range = t.get_range_proof(start, end, 3).unwrap();
+        assert_eq!(range.items.len(), 3);
+        assert!(t.db().loaded() < 20, "{} nodes loaded", t.db().loaded());
+        assert_eq!(verify_range_proof(&root, start, end, &range), Ok(()));
+    }
+
+    #[test]
+    fn verify_range_invalid() {
+        let items = sample(100);
+        let mut t = trie(&items);
+        let root = t.root().unwrap().to_vec();
+        let (start, end) = (&[0x12][..], &[0x80][..]);
+        let range = t.get_range_proof(start, end, 1000).unwrap();
+        assert!(range.items.len() > 3);
+
+        let mut missing = range.clone();
+        missing.items.remove(2);
+        assert_eq!(
+            verify_range_proof(&root, start, end, &missing),
+            Err(ProofError::HashMismatch)
+        );
+
+        let mut first_missing = range.clone();
+        first_missing.items.remove(0);
+        assert_eq!(
+            verify_range_proof(&root, start, end, &first_missing),
+            Err(ProofError::HashMismatch)
+        );
+
+        let mut changed = range.clone();
+        changed.items[1].1.push(0);
+        assert_eq!(
+            verify_range_proof(&root, start, end, &changed),
+            Err(ProofError::HashMismatch)
+        );
+
+        let mut unsorted = range.clone();
+        unsorted.items.swap(0, 1);
+        assert_eq!(
+            verify_range_proof(&root, start, end, &unsorted),
+            Err(ProofError::InvalidRange)
+        );
+
+        let mut incomplete = range.clone();
+        incomplete.proof.pop();
+        assert_eq!(
+            verify_range_proof(&root, start, end, &incomplete),
+            Err(ProofError::IncompleteProof)
+        );
+
+        // an empty range hiding items
+        let mut empty = range.clone();
+        empty.items.clear();
+        assert!(verify_range_proof(&root, start, end, &empty).is_err());
+    }
+
+    #[test]
+    fn verify_multi() {
+        let items = sample(100);
+        let mut t = trie(&items);
+        let root = t.root().unwrap().to_vec();
+
+        let mut keys = items
+            .iter()
+            .step_by(3)
+            .map(|(k, _)| k.clone())
+            .collect::<Vec<_>>();
+        keys.push(vec![0x01, 0x02, 0x03]);
+        let proof = t.get_multiproof(&keys).unwrap();
+        let single = keys
+            .iter()
+            .map(|k| t.get_proof(k).unwrap().len())
+            .sum::<usize>();
+        assert!(proof.len() < single);
+        for (i, node) in proof.iter().enumerate() {
+            assert!(!proof[i + 1..].contains(node));
+        }
+
+        let values = verify_multiproof(&root, &keys, &proof).unwrap();
+        for (key, value) in keys.iter().zip(&values) {
+            let expected = items.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone());
+            assert_eq!(value, &expected);
+        }
+
+        let mut proof = proof;
+        proof.pop();
+        assert_eq!(
+            verify_multiproof(&root, &keys, &proof),
+            Err(ProofError::IncompleteProof)
+        );
+    }
+
+    #[test]
+    fn verify_hasher() {
+        let items = sample(100);
+        let mut t = Trie::<_, Short>::with_hasher(());
+        for (k, v) in &items {
+            t.insert(k, v).unwrap();
+        }
+        let root = t.root().unwrap().to_vec();
+
+        let (k, v) = &items[7];
+        let proof = t.get_proof(k).unwrap();
+        assert_eq!(
+            verify_proof_with_hasher::<Short, _>(&root, k, &proof),
+            Ok(Some(v.clone()))
+        );
+        assert_eq!(
+            verify_proof(&root, k, &proof),
+            Err(ProofError::HashMismatch)
+        );
+
+        let keys = [&items[3].0, &items[40].0];
+        let proof = t.get_multiproof(&keys).unwrap();
+        assert_eq!(
+            verify_multiproof_with_hasher::<Short, _>(&root, &keys, &proof),
+            Ok(vec![Some(items[3].1.clone()), Some(items[40].1.clone())])
+        );
+        let partial = Trie::<_, Short>::from_proof_with_hasher(&root, &proof).unwrap();
+        assert_eq!(partial.get(&items[40].0).unwrap(), Some(&*items[40].1));
+
+        let (start, end) = (&[0x12][..], &[0x80][..]);
+        let range = t.get_range_proof(start, end, 1000).unwrap();
+        assert_eq!(
+            verify_range_proof_with_hasher::<Short, _>(&root, start, end, &range),
+            Ok(())
+        );
+    }
+}
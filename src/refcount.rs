@@ -0,0 +1,471 @@
+//! Reference counting of stored nodes, to prune old tries
+
+use error::TrieError;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+use store::NodeStore;
+
+/// Log record tags: the hashes are referenced, journaled by a commit or released. A
+/// commit record holds both the referenced and the journaled hashes.
+const PUT: u8 = 0;
+const DEREFERENCE: u8 = 1;
+const DELETE: u8 = 2;
+const COMMIT: u8 = 3;
+
+/// Reads a list of hashes from the log at `end`: the number of hashes (4 bytes, little
+/// endian) then each hash prefixed by its length (1 byte)
+///
+/// `end` is moved past the list. Returns `None` if the list is cut by the end of the file.
+fn read_hashes<R: Read>(
+    reader: &mut R,
+    end: &mut u64,
+    file_len: u64,
+) -> Result<Option<Vec<Vec<u8>>>, TrieError> {
+    if *end + 4 > file_len {
+        return Ok(None);
+    }
+    let mut count = [0; 4];
+    reader.read_exact(&mut count)?;
+    *end += 4;
+    let count = u32::from_le_bytes(count);
+    let mut hashes = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let mut hash_len = [0];
+        if *end + 1 > file_len {
+            return Ok(None);
+        }
+        reader.read_exact(&mut hash_len)?;
+        *end += 1 + u64::from(hash_len[0]);
+        if *end > file_len {
+            return Ok(None);
+        }
+        let mut hash = vec![0; hash_len[0] as usize];
+        reader.read_exact(&mut hash)?;
+        hashes.push(hash);
+    }
+    Ok(Some(hashes))
+}
+
+/// A `NodeStore` layer counting the references to each node
+///
+/// A node is referenced once each time a commit writes it and dereferenced once each time
+/// a commit stops using it. Dereferences are only applied `history` commits later: besides
+/// the current one, the tries of the `history` previous roots stay complete. Older ones are
+/// pruned from the inner store while the subtries they share with recent tries stay alive.
+///
+/// With `RefCountStore::new`, reference counts are only kept in memory. A persistent inner
+/// store must be opened with `RefCountStore::open`, which logs the counted operations into
+/// an append-only file and replays them on open.
+#[derive(Debug)]
+pub struct RefCountStore<S: NodeStore> {
+    store: S,
+    refs: HashMap<Vec<u8>, usize>,
+    /// Dereferenced hashes of the last commits, oldest first
+    journal: VecDeque<Vec<Vec<u8>>>,
+    history: usize,
+    /// Log of the operations, if persisted
+    log: Option<File>,
+}
+
+impl<S: NodeStore> RefCountStore<S> {
+    /// Creates a new `RefCountStore` keeping the nodes of the `history` previous roots
+    pub fn new(store: S, history: usize) -> Self {
+        RefCountStore {
+            store,
+            refs: HashMap::new(),
+            journal: VecDeque::with_capacity(history + 1),
+            history,
+            log: None,
+        }
+    }
+
+    /// Opens a `RefCountStore` whose reference counts are logged at `path`
+    ///
+    /// The log is created if it doesn't exist, otherwise the counts are restored from it.
+    /// `store` must be the one the log was written with. A record partially written
+    /// (e.g. on a crash) is discarded.
+    pub fn open<P: AsRef<Path>>(store: S, history: usize, path: P) -> Result<Self, TrieError> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut refcount = RefCountStore::new(store, history);
+        let mut len = 0;
+        {
+            let mut reader = BufReader::new(&file);
+            while len < file_len {
+                let mut tag = [0];
+                reader.read_exact(&mut tag)?;
+                let mut end = len + 1;
+                let hashes = match read_hashes(&mut reader, &mut end, file_len)? {
+                    Some(hashes) => hashes,
+                    None => break,
+                };
+                // nodes were already deleted from the inner store
+                match tag[0] {
+                    PUT => refcount.reference(&hashes),
+                    DEREFERENCE => {
+                        refcount.journal(hashes);
+                    }
+                    COMMIT => match read_hashes(&mut reader, &mut end, file_len)? {
+                        Some(dereferenced) => {
+                            refcount.reference(&hashes);
+                            refcount.journal(dereferenced);
+                        }
+                        None => break,
+                    },
+                    DELETE => {
+                        for hash in &hashes {
+                            refcount.release(hash);
+                        }
+                    }
+                    tag => {
+                        return Err(TrieError::Store(format!("invalid log record {}", tag)));
+                    }
+                }
+                len = end;
+            }
+        }
+        if len < file_len {
+            warn!("discarding {} bytes of incomplete record", file_len - len);
+            file.set_len(len)?;
+        }
+        refcount.log = Some(file);
+        Ok(refcount)
+    }
+
+    /// Get a reference to the inner store
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Get back the inner store
+    pub fn into_store(self) -> S {
+        self.store
+    }
+
+    /// Flush the log to the disk
+    pub fn sync(&self) -> Result<(), TrieError> {
+        match self.log {
+            Some(ref log) => Ok(log.sync_data()?),
+            None => Ok(()),
+        }
+    }
+
+    /// Number of references to the node with this hash
+    pub fn refs(&self, hash: &[u8]) -> usize {
+        self.refs.get(hash).cloned().unwrap_or(0)
+    }
+
+    /// Add one reference to each node
+    fn reference<H: AsRef<[u8]>>(&mut self, hashes: &[H]) {
+        for hash in hashes {
+            *self.refs.entry(hash.as_ref().to_vec()).or_insert(0) += 1;
+        }
+    }
+
+    /// Drop one reference to the node, returns true if it was the last one
+    fn release(&mut self, hash: &[u8]) -> bool {
+        match self.refs.get_mut(hash) {
+            Some(refs) if *refs > 1 => *refs -= 1,
+            Some(_) => {
+                self.refs.remove(hash);
+                return true;
+            }
+            None => warn!("dereferencing unknown node {:?}", hash),
+        }
+        false
+    }
+
+    /// Journal the hashes dereferenced by a commit, returns the nodes to prune
+    fn journal(&mut self, hashes: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        self.journal.push_back(hashes);
+        let mut pruned = Vec::new();
+        while self.journal.len() > self.history {
+            let hashes = self.journal.pop_front().expect("journal is not empty");
+            for hash in hashes {
+                if self.release(&hash) {
+                    pruned.push(hash);
+                }
+            }
+        }
+        pruned
+    }
+
+    /// Append a record made of these lists of hashes to the log, if any
+    fn log(&mut self, tag: u8, lists: &[&[&[u8]]]) -> Result<(), TrieError> {
+        let log = match self.log {
+            Some(ref mut log) => log,
+            None => return Ok(()),
+        };
+        let mut buffer = vec![tag];
+        for hashes in lists {
+            if hashes.iter().any(|hash| hash.len() > u8::MAX as usize)
+                || hashes.len() > u32::MAX as usize
+            {
+                return Err(TrieError::Store("hash too large".to_string()));
+            }
+            buffer.extend_from_slice(&(hashes.len() as u32).to_le_bytes());
+            for hash in hashes.iter() {
+                buffer.push(hash.len() as u8);
+                buffer.extend_from_slice(hash);
+            }
+        }
+        Ok(log.write_all(&buffer)?)
+    }
+
+    /// Write the nodes not stored yet into the inner store
+    fn put_new(&mut self, nodes: &[(&[u8], &[u8])]) -> Result<(), TrieError> {
+        let new = nodes
+            .iter()
+            .filter(|(hash, _)| !self.refs.contains_key(*hash))
+            .cloned()
+            .collect::<Vec<_>>();
+        self.store.put_batch(&new)
+    }
+
+    /// Delete the pruned nodes from the inner store
+    fn prune(&mut self, pruned: Vec<Vec<u8>>) -> Result<(), TrieError> {
+        for hash in pruned {
+            self.store.delete(&hash)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: NodeStore> NodeStore for RefCountStore<S> {
+    fn get(&self, hash: &[u8]) -> Result<Option<Vec<u8>>, TrieError> {
+        self.store.get(hash)
+    }
+
+    fn put(&mut self, hash: &[u8], encoded: &[u8]) -> Result<(), TrieError> {
+        self.put_batch(&[(hash, encoded)])
+    }
+
+    /// Write the new nodes into the inner store and reference all of them
+    fn put_batch(&mut self, nodes: &[(&[u8], &[u8])]) -> Result<(), TrieError> {
+        self.put_new(nodes)?;
+        let hashes = nodes.iter().map(|(hash, _)| *hash).collect::<Vec<_>>();
+        self.log(PUT, &[&hashes])?;
+        self.reference(&hashes);
+        Ok(())
+    }
+
+    /// Journal the dereferenced nodes and prune the ones dereferenced `history` commits ago
+    fn dereference(&mut self, hashes: &[Vec<u8>]) -> Result<(), TrieError> {
+        let logged = hashes.iter().map(|hash| &**hash).collect::<Vec<_>>();
+        self.log(DEREFERENCE, &[&logged])?;
+        let pruned = self.journal(hashes.to_vec());
+        self.prune(pruned)
+    }
+
+    /// Reference the new nodes and journal the dereferenced ones in a single log record
+    ///
+    /// Nothing is counted if the nodes can't be stored or logged. Once logged, the commit
+    /// is applied even if pruning fails, the nodes left are only wasted space.
+    fn commit(
+        &mut self,
+        inserted: &[(&[u8], &[u8])],
+        dereferenced: &[Vec<u8>],
+    ) -> Result<(), TrieError> {
+        self.put_new(inserted)?;
+        let hashes = inserted.iter().map(|(hash, _)| *hash).collect::<Vec<_>>();
+        let logged = dereferenced.iter().map(|hash| &**hash).collect::<Vec<_>>();
+        self.log(COMMIT, &[&hashes, &logged])?;
+        self.reference(&hashes);
+        let pruned = self.journal(dereferenced.to_vec());
+        if let Err(e) = self.prune(pruned) {
+            warn!("cannot prune nodes: {}", e);
+        }
+        Ok(())
+    }
+
+    /// Drop one reference to the node, immediately
+    fn delete(&mut self, hash: &[u8]) -> Result<(), TrieError> {
+        self.log(DELETE, &[&[hash]])?;
+        if self.release(hash) {
+            self.store.delete(hash)?;
+        }
+        Ok(())
+    }
+
+    fn persists(&self) -> bool {
+        self.store.persists()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use keccak_hash::KECCAK_NULL_RLP;
+    use std::collections::HashSet;
+    use std::fs;
+    use testutil::{sample, temp_path, Flaky};
+    use trie::{Changeset, Trie};
+
+    /// The items after each version
+    fn versions() -> Vec<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut items = sample(60);
+        let mut versions = vec![items.clone()];
+        for v in 1..8u8 {
+            for (i, (_, value)) in items.iter_mut().enumerate() {
+                if i % (v as usize + 2) == 0 {
+                    *value = vec![v; 1 + i % 40];
+                }
+            }
+            items.retain(|(k, _)| k[2] % 23 != v);
+            versions.push(items.clone());
+        }
+        versions
+    }
+
+    /// Updates the trie from the `old` items to the `new` ones
+    fn update<S: NodeStore>(
+        t: &mut Trie<S>,
+        old: &[(Vec<u8>, Vec<u8>)],
+        new: &[(Vec<u8>, Vec<u8>)],
+    ) {
+        for (k, _) in old {
+            if !new.iter().any(|(k2, _)| k2 == k) {
+                t.remove(k).unwrap();
+            }
+        }
+        for (k, v) in new {
+            t.insert(k, v).unwrap();
+        }
+    }
+
+    /// The nodes of the tries of `versions[i - history..=i]`
+    fn alive(versions: &[Vec<(Vec<u8>, Vec<u8>)>], i: usize, history: usize) -> HashSet<Vec<u8>> {
+        versions[i.saturating_sub(history)..=i]
+            .iter()
+            .flat_map(|items| nodes(items))
+            .collect()
+    }
+
+    /// All the nodes of a trie
+    fn nodes(items: &[(Vec<u8>, Vec<u8>)]) -> HashSet<Vec<u8>> {
+        let mut store = HashMap::new();
+        {
+            let mut t = Trie::with_store(&mut store);
+            for (k, v) in items {
+                t.insert(k, v).unwrap();
+            }
+        }
+        store.into_keys().collect()
+    }
+
+    #[test]
+    fn changeset() {
+        let versions = versions();
+        let mut t = Trie::with_store(HashMap::new());
+        for (k, v) in &versions[0] {
+            t.insert(k, v).unwrap();
+        }
+        let first = t.commit().unwrap().inserted.len();
+        assert_eq!(first, t.store().len());
+        let root = t.root().unwrap().to_vec();
+
+        t.insert(&versions[0][0].0, [0x42]).unwrap();
+        let dereferenced = t.commit().unwrap().dereferenced;
+        assert!(dereferenced.contains(&root));
+        assert!(dereferenced.iter().all(|h| t.store().contains_key(h)));
+        assert_eq!(t.commit(), Ok(Changeset::default()));
+    }
+
+    #[test]
+    fn prune() {
+        let history = 2;
+        let versions = versions();
+        let mut t = Trie::with_store(RefCountStore::new(HashMap::new(), history));
+        for (i, items) in versions.iter().enumerate() {
+            update(&mut t, &versions[i.saturating_sub(1)], items);
+            t.commit().unwrap();
+
+            // the store holds exactly the nodes of the last tries
+            let stored = t.store().store().keys().cloned().collect::<HashSet<_>>();
+            assert_eq!(stored, alive(&versions, i, history), "version {}", i);
+        }
+    }
+
+    #[test]
+    fn reopen() {
+        let versions = versions();
+        for history in 0..2 {
+            let path = temp_path("refcount");
+            let mut inner = HashMap::new();
+            let mut root = KECCAK_NULL_RLP.to_vec();
+            for (i, items) in versions.iter().enumerate() {
+                // reference counts survive reopening the store before each version
+                let mut store = RefCountStore::open(inner, history, &path).unwrap();
+                {
+                    let mut t = Trie::from_root(&root, &mut store);
+                    update(&mut t, &versions[i.saturating_sub(1)], items);
+                    root = t.root().unwrap().to_vec();
+                }
+                let stored = store.store().keys().cloned().collect::<HashSet<_>>();
+                assert_eq!(stored, alive(&versions, i, history), "version {}", i);
+                store.sync().unwrap();
+                inner = store.into_store();
+            }
+
+            // simulate a crash in the middle of a write
+            let len = fs::metadata(&path).unwrap().len();
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(&[PUT, 2, 0, 0, 0, 32, 1, 2]).unwrap();
+            let store = RefCountStore::open(inner, history, &path).unwrap();
+            assert_eq!(fs::metadata(&path).unwrap().len(), len);
+            assert_eq!(store.refs(&root), 1);
+
+            // a commit record is discarded as a whole
+            let inner = store.into_store();
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            let mut record = vec![COMMIT, 1, 0, 0, 0, 32];
+            record.extend_from_slice(&root);
+            record.extend_from_slice(&[1, 0]);
+            file.write_all(&record).unwrap();
+            let store = RefCountStore::open(inner, history, &path).unwrap();
+            assert_eq!(fs::metadata(&path).unwrap().len(), len);
+            assert_eq!(store.refs(&root), 1);
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn commit_error() {
+        let history = 1;
+        let versions = versions();
+        let path = temp_path("refcount-error");
+        let mut store = RefCountStore::open(Flaky::default(), history, &path).unwrap();
+        let mut clean = RefCountStore::new(HashMap::new(), history);
+        let mut root = KECCAK_NULL_RLP.to_vec();
+        for (i, items) in versions.iter().enumerate() {
+            let old_root = root.clone();
+            {
+                let mut t = Trie::from_root(&root, &mut store);
+                update(&mut t, &versions[i.saturating_sub(1)], items);
+                // the failed commit is neither counted nor logged
+                assert!(t.commit().is_err());
+                t.commit().unwrap();
+                root = t.root().unwrap().to_vec();
+            }
+            {
+                let mut t = Trie::from_root(&old_root, &mut clean);
+                update(&mut t, &versions[i.saturating_sub(1)], items);
+                assert_eq!(t.root().unwrap(), &*root);
+            }
+            assert_eq!(store.refs, clean.refs, "version {}", i);
+            let stored = store.store().nodes.keys().cloned().collect::<HashSet<_>>();
+            assert_eq!(stored, alive(&versions, i, history), "version {}", i);
+        }
+
+        let inner = store.into_store();
+        let store = RefCountStore::open(inner, history, &path).unwrap();
+        assert_eq!(store.refs, clean.refs);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,216 @@
+//! A trie keyed by the keccak hash of the keys, as ethereum state and storage tries
+
+use error::TrieError;
+use iter::DFSIter;
+use keccak_hash::keccak;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use store::NodeStore;
+use trie::{Changeset, Trie};
+
+/// A `Trie` wrapper hashing all keys with keccak
+///
+/// Original keys are lost unless preimages are kept (see `with_preimages`)
+#[derive(Debug)]
+pub struct SecureTrie<S: NodeStore = ()> {
+    trie: Trie<S>,
+    preimages: Option<HashMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl SecureTrie {
+    /// Creates a new `SecureTrie`
+    pub fn new() -> Self {
+        SecureTrie::with_store(())
+    }
+}
+
+impl Default for SecureTrie {
+    fn default() -> Self {
+        SecureTrie::new()
+    }
+}
+
+impl<S: NodeStore> SecureTrie<S> {
+    /// Creates a new empty `SecureTrie` on top of a store
+    pub fn with_store(store: S) -> Self {
+        SecureTrie {
+            trie: Trie::with_store(store),
+            preimages: None,
+        }
+    }
+
+    /// Opens an existing secure trie from its root hash
+    pub fn from_root(root: &[u8], store: S) -> Self {
+        SecureTrie {
+            trie: Trie::from_root(root, store),
+            preimages: None,
+        }
+    }
+
+    /// Keeps the original keys of inserted items so they can be iterated
+    pub fn with_preimages(mut self) -> Self {
+        self.preimages.get_or_insert_with(HashMap::new);
+        self
+    }
+
+    /// Get the underlying trie, keyed by hashes
+    pub fn trie(&self) -> &Trie<S> {
+        &self.trie
+    }
+
+    /// Get the original key of a hashed key, if known
+    pub fn preimage(&self, hash: &[u8]) -> Option<&[u8]> {
+        self.preimages.as_ref()?.get(hash).map(|k| &**k)
+    }
+
+    /// Commit all memory node and returns the trie root
+    pub fn root(&mut self) -> Result<&[u8], TrieError> {
+        self.trie.root()
+    }
+
+    /// Get value corresponding to this key, if its nodes are loaded, see `Trie::get`
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<&[u8]>, TrieError> {
+        self.trie.get(keccak(key.as_ref()))
+    }
+
+    /// Get value corresponding to this key, loading missing nodes from the store
+    pub fn get_loading<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<&[u8]>, TrieError> {
+        self.trie.get_loading(keccak(key.as_ref()))
+    }
+
+    /// Get the merkle proof of the value at this key, see `Trie::get_proof`
+    pub fn get_proof<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Vec<Vec<u8>>, TrieError> {
+        self.trie.get_proof(keccak(key.as_ref()))
+    }
+
+    /// Insert a (key, value)
+    ///
+    /// Returns the old value if any
+    pub fn insert<K, V>(&mut self, key: K, value: V) -> Result<Option<&[u8]>, TrieError>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let key = key.as_ref();
+        let hash = keccak(key);
+        if let Some(ref mut preimages) = self.preimages {
+            preimages.insert(hash.to_vec(), key.to_vec());
+        }
+        self.trie.insert(hash, value)
+    }
+
+    /// Remove the value at this key and returns it, if any
+    pub fn remove<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<&[u8]>, TrieError> {
+        let hash = keccak(key.as_ref());
+        if let Some(ref mut preimages) = self.preimages {
+            preimages.remove(&hash[..]);
+        }
+        self.trie.remove(hash)
+    }
+
+    /// Commit all memory nodes, new nodes are written into the store
+    pub fn commit(&mut self) -> Result<Changeset<'_>, TrieError> {
+        self.trie.commit()
+    }
+
+    /// Iterates over all items, in hashed key order
+    ///
+    /// Yields the original keys when their preimage is known, the hashed keys otherwise
+    pub fn iter(&self) -> SecureIter<'_> {
+        SecureIter {
+            iter: self.trie.iter(),
+            preimages: self.preimages.as_ref(),
+        }
+    }
+
+    /// Iterates over all items, in hashed key order, loading the nodes from the store
+    pub fn iter_loading(
+        &mut self,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>), TrieError>> + '_ {
+        let preimages = self.preimages.as_ref();
+        self.trie.iter_loading().map(move |item| {
+            let (hash, value) = item?;
+            match preimages.and_then(|p| p.get(&hash)) {
+                Some(key) => Ok((key.clone(), value)),
+                None => Ok((hash, value)),
+            }
+        })
+    }
+}
+
+/// An iterator over a `SecureTrie` items, replacing hashed keys by their preimage
+pub struct SecureIter<'a> {
+    iter: DFSIter<'a>,
+    preimages: Option<&'a HashMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl<'a> Iterator for SecureIter<'a> {
+    type Item = (Cow<'a, [u8]>, &'a [u8]);
+    fn next(&mut self) -> Option<Self::Item> {
+        let (hash, value) = self.iter.next()?;
+        match self.preimages.and_then(|p| p.get(&*hash)) {
+            Some(key) => Some((Cow::Borrowed(&**key), value)),
+            None => Some((hash, value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use keccak_hasher::KeccakHasher;
+    use testutil::sample;
+    use triehash::sec_trie_root;
+
+    #[test]
+    fn sec_root() {
+        let mut items = sample(50);
+        let mut t = SecureTrie::new();
+        for (k, v) in &items {
+            t.insert(k, v).unwrap();
+        }
+        assert_eq!(
+            t.root().unwrap(),
+            &*sec_trie_root::<KeccakHasher, _, _, _>(items.clone())
+        );
+        for (k, v) in &items {
+            assert_eq!(t.get(k).unwrap(), Some(&**v));
+        }
+        assert_eq!(t.trie.get(&items[0].0).unwrap(), None);
+
+        let (k, v) = items.remove(10);
+        assert_eq!(t.remove(&k).unwrap(), Some(&*v));
+        assert_eq!(t.get(&k).unwrap(), None);
+        assert_eq!(
+            t.root().unwrap(),
+            &*sec_trie_root::<KeccakHasher, _, _, _>(items)
+        );
+    }
+
+    #[test]
+    fn preimages() {
+        let mut items = sample(50);
+        let mut t = SecureTrie::new().with_preimages();
+        for (k, v) in &items {
+            t.insert(k, v).unwrap();
+        }
+        let (k, _) = items.remove(10);
+        t.remove(&k).unwrap();
+        assert_eq!(t.preimage(&keccak(&k)), None);
+        assert_eq!(t.preimage(&keccak(&items[0].0)), Some(&*items[0].0));
+
+        let mut found = t
+            .iter()
+            .map(|(k, v)| (k.to_vec(), v.to_vec()))
+            .collect::<Vec<_>>();
+        found.sort();
+        items.sort();
+        assert_eq!(found, items);
+
+        // without preimages, keys are hashed
+        let mut t = SecureTrie::new();
+        t.insert(&items[0].0, &items[0].1).unwrap();
+        let keys = t.iter().map(|(k, _)| k.to_vec()).collect::<Vec<_>>();
+        assert_eq!(keys, vec![keccak(&items[0].0).to_vec()]);
+    }
+}
@@ -0,0 +1,176 @@
+use error::TrieError;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// A backend storing rlp encoded nodes by their hash
+///
+/// Nodes are written on `Trie::commit` and read back lazily when the trie
+/// needs a node it doesn't have in memory
+pub trait NodeStore {
+    /// Get the encoded node with this hash
+    fn get(&self, hash: &[u8]) -> Result<Option<Vec<u8>>, TrieError>;
+
+    /// Store an encoded node by its hash
+    fn put(&mut self, hash: &[u8], encoded: &[u8]) -> Result<(), TrieError>;
+
+    /// Store several (hash, encoded node) at once
+    fn put_batch(&mut self, nodes: &[(&[u8], &[u8])]) -> Result<(), TrieError> {
+        for (hash, encoded) in nodes {
+            self.put(hash, encoded)?;
+        }
+        Ok(())
+    }
+
+    /// Notifies that a commit dereferenced these nodes
+    ///
+    /// By default nothing is done and all nodes are kept (archive), see `RefCountStore`
+    /// to prune them.
+    fn dereference(&mut self, _hashes: &[Vec<u8>]) -> Result<(), TrieError> {
+        Ok(())
+    }
+
+    /// Stores the nodes written by a commit and notifies the ones it dereferenced
+    ///
+    /// Called once per commit. By default `put_batch` then `dereference` are called. A
+    /// failed commit is written again, so stores counting references must apply either
+    /// both or none.
+    fn commit(
+        &mut self,
+        inserted: &[(&[u8], &[u8])],
+        dereferenced: &[Vec<u8>],
+    ) -> Result<(), TrieError> {
+        self.put_batch(inserted)?;
+        self.dereference(dereferenced)
+    }
+
+    /// Delete the node with this hash
+    ///
+    /// By default the node is kept
+    fn delete(&mut self, _hash: &[u8]) -> Result<(), TrieError> {
+        Ok(())
+    }
+
+    /// Returns true if the stored nodes can be read back
+    ///
+    /// Nodes are only evicted from memory if they can be loaded again.
+    fn persists(&self) -> bool {
+        true
+    }
+}
+
+/// A store whose clones are handles to the same backend, see `Trie::fork`
+pub trait SharedStore: NodeStore + Clone {}
+
+/// No backend: nodes only live in the `Trie` memory
+impl NodeStore for () {
+    fn get(&self, _hash: &[u8]) -> Result<Option<Vec<u8>>, TrieError> {
+        Ok(None)
+    }
+
+    fn put(&mut self, _hash: &[u8], _encoded: &[u8]) -> Result<(), TrieError> {
+        Ok(())
+    }
+
+    fn put_batch(&mut self, _nodes: &[(&[u8], &[u8])]) -> Result<(), TrieError> {
+        Ok(())
+    }
+
+    fn persists(&self) -> bool {
+        false
+    }
+}
+
+impl SharedStore for () {}
+
+impl NodeStore for HashMap<Vec<u8>, Vec<u8>> {
+    fn get(&self, hash: &[u8]) -> Result<Option<Vec<u8>>, TrieError> {
+        Ok(HashMap::get(self, hash).cloned())
+    }
+
+    fn put(&mut self, hash: &[u8], encoded: &[u8]) -> Result<(), TrieError> {
+        self.insert(hash.to_vec(), encoded.to_vec());
+        Ok(())
+    }
+
+    fn delete(&mut self, hash: &[u8]) -> Result<(), TrieError> {
+        self.remove(hash);
+        Ok(())
+    }
+}
+
+impl<S: NodeStore> NodeStore for &mut S {
+    fn get(&self, hash: &[u8]) -> Result<Option<Vec<u8>>, TrieError> {
+        (**self).get(hash)
+    }
+
+    fn put(&mut self, hash: &[u8], encoded: &[u8]) -> Result<(), TrieError> {
+        (**self).put(hash, encoded)
+    }
+
+    fn put_batch(&mut self, nodes: &[(&[u8], &[u8])]) -> Result<(), TrieError> {
+        (**self).put_batch(nodes)
+    }
+
+    fn dereference(&mut self, hashes: &[Vec<u8>]) -> Result<(), TrieError> {
+        (**self).dereference(hashes)
+    }
+
+    fn commit(
+        &mut self,
+        inserted: &[(&[u8], &[u8])],
+        dereferenced: &[Vec<u8>],
+    ) -> Result<(), TrieError> {
+        (**self).commit(inserted, dereferenced)
+    }
+
+    fn delete(&mut self, hash: &[u8]) -> Result<(), TrieError> {
+        (**self).delete(hash)
+    }
+
+    fn persists(&self) -> bool {
+        (**self).persists()
+    }
+}
+
+/// Any store shared between threads, e.g. `Arc<RwLock<FileStore>>`
+impl<S: NodeStore> NodeStore for Arc<RwLock<S>> {
+    fn get(&self, hash: &[u8]) -> Result<Option<Vec<u8>>, TrieError> {
+        self.read().map_err(poisoned)?.get(hash)
+    }
+
+    fn put(&mut self, hash: &[u8], encoded: &[u8]) -> Result<(), TrieError> {
+        self.write().map_err(poisoned)?.put(hash, encoded)
+    }
+
+    fn put_batch(&mut self, nodes: &[(&[u8], &[u8])]) -> Result<(), TrieError> {
+        self.write().map_err(poisoned)?.put_batch(nodes)
+    }
+
+    fn dereference(&mut self, hashes: &[Vec<u8>]) -> Result<(), TrieError> {
+        self.write().map_err(poisoned)?.dereference(hashes)
+    }
+
+    fn commit(
+        &mut self,
+        inserted: &[(&[u8], &[u8])],
+        dereferenced: &[Vec<u8>],
+    ) -> Result<(), TrieError> {
+        self.write()
+            .map_err(poisoned)?
+            .commit(inserted, dereferenced)
+    }
+
+    fn delete(&mut self, hash: &[u8]) -> Result<(), TrieError> {
+        self.write().map_err(poisoned)?.delete(hash)
+    }
+
+    fn persists(&self) -> bool {
+        self.read().map(|store| store.persists()).unwrap_or(false)
+    }
+}
+
+impl<S: NodeStore> SharedStore for Arc<RwLock<S>> {}
+
+fn poisoned<T>(_: T) -> TrieError {
+    TrieError::Store("poisoned lock".to_string())
+}
@@ -0,0 +1,96 @@
+//! Fixtures shared by the tests
+
+use error::TrieError;
+use hasher::Hasher;
+use keccak_hash::keccak;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use store::NodeStore;
+
+/// Keccak truncated to 20 bytes
+#[derive(Debug)]
+pub struct Short;
+
+impl Hasher for Short {
+    type Out = Vec<u8>;
+    const LENGTH: usize = 20;
+    fn hash(data: &[u8]) -> Vec<u8> {
+        keccak(data)[..][..20].to_vec()
+    }
+}
+
+/// A store failing every other batch, starting with the first one
+#[derive(Debug, Default)]
+pub struct Flaky {
+    pub nodes: HashMap<Vec<u8>, Vec<u8>>,
+    /// The last batch failed
+    failed: bool,
+}
+
+impl NodeStore for Flaky {
+    fn get(&self, hash: &[u8]) -> Result<Option<Vec<u8>>, TrieError> {
+        Ok(self.nodes.get(hash).cloned())
+    }
+
+    fn put(&mut self, hash: &[u8], encoded: &[u8]) -> Result<(), TrieError> {
+        self.nodes.insert(hash.to_vec(), encoded.to_vec());
+        Ok(())
+    }
+
+    fn put_batch(&mut self, nodes: &[(&[u8], &[u8])]) -> Result<(), TrieError> {
+        self.failed = !self.failed;
+        if self.failed {
+            return Err(TrieError::Store("flaky".to_string()));
+        }
+        for (hash, encoded) in nodes {
+            self.put(hash, encoded)?;
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, hash: &[u8]) -> Result<(), TrieError> {
+        self.nodes.remove(hash);
+        Ok(())
+    }
+}
+
+/// `n` items with 3 bytes keys, many of them sharing a prefix, and values of 1 to 40 bytes
+pub fn sample(n: u8) -> Vec<(Vec<u8>, Vec<u8>)> {
+    (0..n)
+        .map(|i| {
+            (
+                vec![i.wrapping_mul(37), i % 5, i],
+                vec![i; 1 + i as usize % 40],
+            )
+        })
+        .collect()
+}
+
+/// About `n` items sorted by key, with keys of 1 to 32 bytes, some of them prefixes of others
+pub fn mixed_sample(n: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut items = (0..n)
+        .map(|i| {
+            let key = match i % 4 {
+                0 => vec![(i % 7) as u8],
+                1 => vec![(i % 7) as u8, (i / 3) as u8],
+                2 => vec![(i % 7) as u8, 0x10 | (i % 3) as u8, (i / 5) as u8],
+                _ => (0..32).map(|j| (i * 31 + j * 7) as u8).collect(),
+            };
+            (key, vec![i as u8; 1 + i % 40])
+        })
+        .collect::<Vec<_>>();
+    // the first value of a duplicated key is kept
+    items.sort_by(|a, b| a.0.cmp(&b.0));
+    items.dedup_by(|a, b| a.0 == b.0);
+    items
+}
+
+/// A path in the temporary directory, removed if it exists
+pub fn temp_path(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("quick-trie-{}-{}", name, process::id()));
+    let _ = fs::remove_file(&path);
+    path
+}
@@ -1,16 +1,71 @@
 use arena::{Arena, ArenaSlice};
 use db::{Db, Index};
-use iter::DFSIter;
+use diff::{self, Change, Side};
+use error::TrieError;
+use hasher::{Hasher, Keccak};
+use iter::{DFSIter, LoadingIter, RangeIter};
 use nibbles::Nibble;
 use node::{Branch, Extension, Leaf, Node};
-use std::cmp::min;
+use proof::{proof_store, RangeProof};
+use std::cmp::{min, Ordering};
+use std::collections::HashSet;
+use std::marker::PhantomData;
 use std::mem;
+use std::ops::{Bound, RangeBounds};
+use store::{NodeStore, SharedStore};
+
+/// The nodes written and dereferenced by a commit
+#[derive(Debug, Default, PartialEq)]
+pub struct Changeset<'a> {
+    /// New (hash, encoded node) pairs
+    pub inserted: Vec<(&'a [u8], &'a [u8])>,
+    /// Hashes of the stored nodes the trie doesn't reference anymore
+    pub dereferenced: Vec<Vec<u8>>,
+}
 
 /// A patricia trie
+///
+/// Nodes are kept in memory and, if any, persisted into a `NodeStore` backend
+/// on commit. Nodes missing from memory are lazily loaded from the store.
+///
+/// Nodes are hashed with `H`, keccak by default.
 #[derive(Debug)]
-pub struct Trie {
+pub struct Trie<S: NodeStore = (), H: Hasher = Keccak> {
     arena: Arena,
     db: Db,
+    store: S,
+    /// Maximum memory used by the nodes after a commit, in bytes
+    budget: Option<usize>,
+    /// The last commit exceeded the budget
+    over_budget: bool,
+    /// Pending changes are committed on drop, except for forks
+    commit_on_drop: bool,
+    hasher: PhantomData<H>,
+}
+
+/// A read-only view of a trie, see `Trie::snapshot`
+///
+/// Reads go through a temporary fork: the nodes the snapshot doesn't hold are loaded from
+/// the store on each read, and dropped afterwards.
+#[derive(Debug)]
+pub struct Snapshot<S: NodeStore = (), H: Hasher = Keccak> {
+    trie: Trie<S, H>,
+}
+
+/// The bounds of `range`, as slices
+fn bounds<'a, K, R>(range: &'a R) -> (Bound<&'a [u8]>, Bound<&'a [u8]>)
+where
+    K: AsRef<[u8]> + 'a,
+    R: RangeBounds<K>,
+{
+    fn bound<K: AsRef<[u8]>>(bound: Bound<&K>) -> Bound<&[u8]> {
+        match bound {
+            Bound::Included(k) => Bound::Included(k.as_ref()),
+            Bound::Excluded(k) => Bound::Excluded(k.as_ref()),
+            Bound::Unbounded => Bound::Unbounded,
+        }
+    }
+    (bound(range.start_bound()), bound(range.end_bound()))
 }
 
 #[derive(Debug)]
@@ -24,63 +79,164 @@ enum Action {
 impl Trie {
     /// Creates a new `Trie`
     pub fn new() -> Self {
+        Trie::with_store(())
+    }
+
+    /// Creates a partial trie out of proof nodes, e.g. from `Trie::get_multiproof`
+    ///
+    /// Nodes reachable from `root` are loaded, other subtries are left as unresolved hashes.
+    /// Proven paths can be read and updated and `root` is still computed correctly, while
+    /// touching an unresolved node fails with `TrieError::MissingNode`.
+    pub fn from_proof(root: &[u8], nodes: &[Vec<u8>]) -> Result<Self, TrieError> {
+        Trie::from_proof_with_hasher(root, nodes)
+    }
+}
+
+impl<H: Hasher> Trie<(), H> {
+    /// Creates a partial trie out of proof nodes hashed with `H`, see `Trie::from_proof`
+    pub fn from_proof_with_hasher(root: &[u8], nodes: &[Vec<u8>]) -> Result<Self, TrieError> {
+        let store = proof_store::<H>(nodes);
+        let mut trie = Trie::from_root_with_hasher(root, ());
+        let root = trie.db.root_index();
+        trie.import_from(&store, root, true)?;
+        Ok(trie)
+    }
+}
+
+impl<S: NodeStore> Trie<S> {
+    /// Creates a new empty `Trie` on top of a store
+    pub fn with_store(store: S) -> Self {
+        Trie::with_hasher(store)
+    }
+
+    /// Opens an existing trie from its root hash
+    ///
+    /// No node is read until needed, they are then lazily loaded from the store
+    pub fn from_root(root: &[u8], store: S) -> Self {
+        Trie::from_root_with_hasher(root, store)
+    }
+}
+
+impl<S: NodeStore, H: Hasher> Trie<S, H> {
+    /// Creates a new empty `Trie` on top of a store, hashing nodes with `H`
+    ///
+    /// e.g. `Trie::<_, MyHasher>::with_hasher(store)`
+    pub fn with_hasher(store: S) -> Self {
         let mut arena = Arena::new();
-        let db = Db::new(&mut arena);
-        Trie { arena, db }
+        let db = Db::new::<H>(&mut arena);
+        Trie {
+            arena,
+            db,
+            store,
+            budget: None,
+            over_budget: false,
+            commit_on_drop: true,
+            hasher: PhantomData,
+        }
+    }
+
+    /// Opens an existing trie from its root hash, hashing nodes with `H`
+    pub fn from_root_with_hasher(root: &[u8], store: S) -> Self {
+        let mut trie = Trie::with_hasher(store);
+        if root != H::null_node().as_ref() {
+            let root = trie.arena.push(root);
+            trie.db.set_root(Index::Hash(root));
+        }
+        trie
+    }
+
+    /// Limits the memory used by the nodes to about `budget` bytes
+    ///
+    /// When a commit leaves more than `budget` bytes in memory, the deepest nodes are evicted
+    /// down to half the budget, before the next operation. They are loaded again when
+    /// needed, so the budget is ignored if the store doesn't persist nodes (`()`).
+    pub fn with_memory_budget(mut self, budget: usize) -> Self {
+        if self.store.persists() {
+            self.budget = Some(budget);
+        } else {
+            warn!("store doesn't persist nodes, ignoring memory budget");
+        }
+        self
+    }
+
+    /// Approximate memory used by the nodes, in bytes, nodes shared with forks excluded
+    pub fn memory_usage(&self) -> usize {
+        self.arena.size() + self.db.loaded() * mem::size_of::<Node>()
+    }
+
+    /// Evicts nodes if the last commit exceeded the memory budget
+    ///
+    /// Eviction is delayed while checkpoints are open.
+    fn apply_budget(&mut self) {
+        if self.db.checkpoints() > 0 {
+            return;
+        }
+        if let (true, Some(budget)) = (self.over_budget, self.budget) {
+            self.db.evict::<H>(&mut self.arena, budget / 2);
+            self.over_budget = false;
+        }
     }
 
-    /// Import values from an external source
-    pub fn import<F: Fn(&[u8]) -> Option<Vec<u8>>>(&mut self, get: F) {
-        if let Index::Hash(h) = self.db.root_index() {
-            self.import_root(get, h);
+    /// Get a reference to the underlying store
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Make sure the node at `key` is in memory, loading it from the store if needed
+    pub(crate) fn load(&mut self, key: Index) -> Result<(), TrieError> {
+        match key {
+            Index::Hash(h) if !self.db.contains(h) => {
+                self.db.load::<H, _>(h, &self.store, &mut self.arena)
+            }
+            _ => Ok(()),
         }
     }
 
-    fn import_root<F: Fn(&[u8]) -> Option<Vec<u8>>>(&mut self, get: F, root: usize) {
-        self.commit();
+    /// Import all nodes from an external store
+    pub fn import<T: NodeStore>(&mut self, store: &T) -> Result<(), TrieError> {
+        self.commit()?;
+        let root = self.db.root_index();
+        self.import_from(store, root, false)
+    }
+
+    /// Import the subtrie at `root`, skipping the nodes missing from `store` if `partial`
+    fn import_from<T: NodeStore>(
+        &mut self,
+        store: &T,
+        root: Index,
+        partial: bool,
+    ) -> Result<(), TrieError> {
         let mut stack = vec![root];
 
         while let Some(key) = stack.pop() {
             debug!("Searching key {:?}", key);
-            let node = match get(&self.arena[key])
-                .and_then(|v| Node::try_from_encoded(&v, &mut self.arena))
-            {
-                None => return,
-                Some(node) => node,
-            };
-
-            match node {
-                Node::Branch(ref branch) => {
-                    stack.extend(branch.keys.iter().filter_map(|k| match k {
-                        Some(Index::Hash(h)) => Some(h),
-                        _ => None,
-                    }));
-                }
-                Node::Extension(ref extension) => {
-                    if let Index::Hash(h) = extension.key {
-                        stack.push(h);
+            if let Index::Hash(h) = key {
+                if !self.db.contains(h) {
+                    match self.db.load::<H, _>(h, store, &mut self.arena) {
+                        Err(TrieError::MissingNode(_)) if partial => continue,
+                        r => r?,
                     }
                 }
-                _ => (),
             }
 
-            // insert this node
-            self.db.insert_node(Index::Hash(key), node);
+            match self.db.get(&key) {
+                Some(Node::Branch(ref branch)) => stack.extend(branch.keys.iter().flatten()),
+                Some(Node::Extension(ref extension)) => stack.push(extension.key),
+                _ => (),
+            }
         }
+        Ok(())
     }
 
-    /// Import values from an external source and starting with prefix (or less)
-    pub fn import_prefix<F>(&mut self, get: F, prefix: &[u8])
-    where
-        F: Fn(&[u8]) -> Option<Vec<u8>>,
-    {
-        self.commit();
-
-        let mut key = if let Index::Hash(h) = self.db.root_index() {
-            h
-        } else {
-            return;
-        };
+    /// Import nodes from an external store, only for keys starting with prefix
+    ///
+    /// Nodes on the path to the prefix are imported as well
+    pub fn import_prefix<T: NodeStore>(
+        &mut self,
+        store: &T,
+        prefix: &[u8],
+    ) -> Result<(), TrieError> {
+        self.commit()?;
 
         // create a nibble out of the prefix
         let data = &[prefix];
@@ -92,49 +248,44 @@ impl Trie {
         };
 
         // advance until we find node with this prefix
-        while nibble.len() > 0 {
-            let node = match get(&self.arena[key])
-                .and_then(|v| Node::try_from_encoded(&v, &mut self.arena))
-            {
-                None => return,
-                Some(node) => node,
-            };
+        let mut key = self.db.root_index();
+        loop {
+            if let Index::Hash(h) = key {
+                if !self.db.contains(h) {
+                    self.db.load::<H, _>(h, store, &mut self.arena)?;
+                }
+            }
 
-            match node {
-                Node::Branch(ref branch) => {
-                    if let Some((p, n)) = nibble.pop_front(arena) {
-                        if let Some(k) = branch.keys[p as usize] {
-                            if let Index::Hash(h) = k {
-                                key = h;
-                                nibble = n;
-                            }
+            match self.db.get(&key) {
+                Some(Node::Branch(ref branch)) => match nibble.pop_front(arena) {
+                    Some((p, n)) => match branch.keys[p as usize] {
+                        Some(k) => {
+                            key = k;
+                            nibble = n;
                         }
-                    }
-                }
-                Node::Extension(ref extension) => {
+                        None => return Ok(()),
+                    },
+                    None => break,
+                },
+                Some(Node::Extension(ref extension)) => {
                     let min = min(nibble.len(), extension.nibble.len());
                     let (left, right) = nibble.split_at(min);
                     let (eleft, _) = extension.nibble.split_at(min);
-                    if left.eq(&eleft, arena, &self.arena) {
-                        if let Index::Hash(h) = extension.key {
-                            key = h;
-                        }
-                        if let Some(r) = right {
-                            nibble = r;
-                        } else {
-                            break;
-                        }
+                    if !left.eq(&eleft, arena, &self.arena) {
+                        return Ok(());
+                    }
+                    key = extension.key;
+                    match right {
+                        Some(r) => nibble = r,
+                        None => break,
                     }
                 }
-                _ => (),
+                _ => break,
             }
-
-            // insert this node
-            self.db.insert_node(Index::Hash(key), node);
         }
 
         // import the subtrie
-        self.import_root(get, key);
+        self.import_from(store, key, false)
     }
 
     pub(crate) fn db(&self) -> &Db {
@@ -146,13 +297,37 @@ impl Trie {
     }
 
     /// Commit all memory node and returns the trie root
-    pub fn root(&mut self) -> Option<&[u8]> {
-        self.commit();
-        self.db.root(&self.arena)
+    pub fn root(&mut self) -> Result<&[u8], TrieError> {
+        self.commit()?;
+        self.db
+            .root(&self.arena)
+            .ok_or(TrieError::Corrupted("root is not hashed after commit"))
+    }
+
+    /// Get value correspding to this path
+    ///
+    /// Fails with `TrieError::MissingNode` if a node on the path is not loaded, see
+    /// `get_loading`.
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<&[u8]>, TrieError> {
+        let data = key.as_ref();
+        let nibble = Nibble {
+            data: 0,
+            start: 0,
+            end: data.len() as u32 * 2,
+        };
+        let data = &[data];
+        let arena = &ArenaSlice(data.as_ref());
+        match self.get_nibble(nibble, arena) {
+            Ok(value) => Ok(value.map(|v| &self.arena[v])),
+            Err(hash) => Err(TrieError::MissingNode(self.arena[hash].to_vec())),
+        }
     }
 
     /// Get value correspding to this path
-    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Option<&[u8]> {
+    ///
+    /// Missing nodes are loaded from the store
+    pub fn get_loading<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<&[u8]>, TrieError> {
+        self.apply_budget();
         let data = key.as_ref();
         let nibble = Nibble {
             data: 0,
@@ -161,25 +336,61 @@ impl Trie {
         };
         let data = &[data];
         let arena = &ArenaSlice(data.as_ref());
-        self.get_nibble(nibble, arena)
+        let value = self.lookup(&nibble, arena)?;
+        Ok(value.map(move |v| &self.arena[v]))
+    }
+
+    /// Load the nodes on the path of `key`
+    pub(crate) fn load_path(&mut self, key: &[u8]) -> Result<(), TrieError> {
+        let nibble = Nibble {
+            data: 0,
+            start: 0,
+            end: key.len() as u32 * 2,
+        };
+        let data = &[key];
+        let arena = &ArenaSlice(data.as_ref());
+        self.lookup(&nibble, arena).map(|_| ())
+    }
+
+    /// Get the value index corresponding to that path, loading missing nodes
+    fn lookup<A>(&mut self, path: &Nibble, arena: &A) -> Result<Option<usize>, TrieError>
+    where
+        A: ::std::ops::Index<usize, Output = [u8]>,
+    {
+        loop {
+            match self.get_nibble(path.clone(), arena) {
+                Ok(value) => return Ok(value),
+                Err(hash) => self.load(Index::Hash(hash))?,
+            }
+        }
     }
 
-    /// Get the item corresponding to that key
-    fn get_nibble<A>(&self, mut path: Nibble, arena: &A) -> Option<&[u8]>
+    /// Get the value index corresponding to that path
+    ///
+    /// Returns the hash index of the first node not in memory, if any
+    fn get_nibble<A>(&self, mut path: Nibble, arena: &A) -> Result<Option<usize>, usize>
     where
         A: ::std::ops::Index<usize, Output = [u8]>,
     {
         let mut key = self.db.root_index();
         loop {
             debug!("Searching key {:?}", key);
-            match self.db.get(&key)? {
+            let node = match (self.db.get(&key), key) {
+                (Some(node), _) => node,
+                (None, Index::Hash(hash)) => return Err(hash),
+                (None, Index::Memory(_)) => return Ok(None),
+            };
+            match node {
                 Node::Branch(ref branch) => {
                     debug!("key {:?}: {:?}", key, branch);
                     if let Some((u, n)) = path.pop_front(arena) {
-                        key = branch.keys[u as usize]?;
+                        key = match branch.keys[u as usize] {
+                            Some(k) => k,
+                            None => return Ok(None),
+                        };
                         path = n;
                     } else {
-                        return branch.value.map(|idx| &self.arena[idx]);
+                        return Ok(branch.value);
                     }
                 }
                 Node::Extension(ref extension) => {
@@ -189,25 +400,131 @@ impl Trie {
                         path = right.unwrap_or_default();
                         key = extension.key;
                     } else {
-                        return None;
+                        return Ok(None);
                     }
                 }
                 Node::Leaf(ref leaf) => {
                     debug!("key {:?}: {:?}", key, leaf);
                     return if leaf.nibble.eq(&path, &self.arena, arena) {
-                        Some(&self.arena[leaf.value])
+                        Ok(Some(leaf.value))
                     } else {
                         warn!("wrong nibble");
-                        None
+                        Ok(None)
                     };
                 }
-                Node::Empty => return None,
+                Node::Empty => return Ok(None),
+            }
+        }
+    }
+
+    /// Get the merkle proof of the value (or of its absence) at this key
+    ///
+    /// Commits the trie and returns the rlp encoded nodes met while searching for the key,
+    /// from the root down to the last node of the path. Nodes inlined into their parent
+    /// are not repeated.
+    pub fn get_proof<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Vec<Vec<u8>>, TrieError> {
+        self.get_multiproof(&[key])
+    }
+
+    /// Get the merkle proof of several keys at once
+    ///
+    /// Nodes shared by several keys paths are only returned once, in the order they are met
+    pub fn get_multiproof<K: AsRef<[u8]>>(
+        &mut self,
+        keys: &[K],
+    ) -> Result<Vec<Vec<u8>>, TrieError> {
+        self.commit()?;
+        let mut seen = HashSet::new();
+        let mut proof = Vec::new();
+        for key in keys {
+            self.push_proof(key.as_ref(), &mut seen, &mut proof)?;
+        }
+        Ok(proof)
+    }
+
+    /// Push the nodes on the path of `key` whose hash is not already `seen`
+    fn push_proof(
+        &mut self,
+        data: &[u8],
+        seen: &mut HashSet<Vec<u8>>,
+        proof: &mut Vec<Vec<u8>>,
+    ) -> Result<(), TrieError> {
+        let mut path = Nibble {
+            data: 0,
+            start: 0,
+            end: data.len() as u32 * 2,
+        };
+        let data = &[data];
+        let arena = &ArenaSlice(data.as_ref());
+
+        let mut key = self.db.root_index();
+        loop {
+            self.load(key)?;
+            let node = match self.db.get(&key) {
+                Some(node) => node,
+                None => return Err(TrieError::Corrupted("missing memory node")),
+            };
+            if let Index::Hash(i) = key {
+                if self.arena[i].len() == H::LENGTH && seen.insert(self.arena[i].to_vec()) {
+                    proof.extend(node.encoded_vec::<H, _>(&self.arena));
+                }
+            }
+            match node {
+                Node::Branch(ref branch) => match path.pop_front(arena) {
+                    Some((u, n)) => match branch.keys[u as usize] {
+                        Some(k) => {
+                            key = k;
+                            path = n;
+                        }
+                        None => break,
+                    },
+                    None => break,
+                },
+                Node::Extension(ref extension) => {
+                    let (left, right) = path.split_at(extension.nibble.len());
+                    if !extension.nibble.eq(&left, &self.arena, arena) {
+                        break;
+                    }
+                    path = right.unwrap_or_default();
+                    key = extension.key;
+                }
+                Node::Leaf(_) | Node::Empty => break,
             }
         }
+        Ok(())
+    }
+
+    /// Get the items with a key between `start` and `end` (both included), at most `limit`,
+    /// along with the proof that there is no other item in the trie within that range
+    ///
+    /// The proof is made of the nodes proving `start` and the last item key (or `end` if
+    /// there is no item), see `proof::verify_range_proof`.
+    pub fn get_range_proof<K: AsRef<[u8]>>(
+        &mut self,
+        start: K,
+        end: K,
+        limit: usize,
+    ) -> Result<RangeProof, TrieError> {
+        self.commit()?;
+        let (start, end) = (start.as_ref(), end.as_ref());
+
+        let items = self
+            .range_loading(start..=end)?
+            .take(limit)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let right = items.last().map_or(end, |(k, _)| &**k);
+        let proof = self.get_multiproof(&[start, right])?;
+        Ok(RangeProof { items, proof })
     }
 
     /// Insert a (key, value)
-    pub fn insert<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Option<&[u8]> {
+    pub fn insert<K, V>(&mut self, key: K, value: V) -> Result<Option<&[u8]>, TrieError>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.apply_budget();
         let key = key.as_ref();
         let value = value.as_ref();
         let data = &[key, value];
@@ -222,21 +539,33 @@ impl Trie {
     }
 
     /// Insert a new leaf
-    fn insert_leaf<A>(&mut self, leaf: Leaf, arena: &A) -> Option<&[u8]>
+    fn insert_leaf<A>(&mut self, leaf: Leaf, arena: &A) -> Result<Option<&[u8]>, TrieError>
     where
         A: ::std::ops::Index<usize, Output = [u8]>,
     {
         let value = self.arena.push(&arena[leaf.value]);
         let mut key = self.db.root_index();
         let mut path = leaf.nibble;
+        let mut parent = None;
 
         let action = loop {
+            self.load(key)?;
+            if let Some((parent, nibble)) = parent {
+                // the node may be moved (or copied) into memory, its parent must
+                // refer to the new index
+                let old_key = key;
+                self.db.get_mut(&mut key);
+                if key != old_key {
+                    self.db.set_child(parent, nibble, key);
+                }
+            }
             match self.db.get_mut(&mut key) {
                 Some(Node::Branch(ref mut branch)) => {
                     if let Some((u, n)) = path.pop_front(arena) {
-                        let mut k = branch.keys[u as usize];
+                        let k = branch.keys[u as usize];
                         match k {
                             Some(ref k) => {
+                                parent = Some((key, Some(u)));
                                 key = *k;
                                 path = n;
                             }
@@ -248,9 +577,9 @@ impl Trie {
                         }
                     } else {
                         // update branch value
-                        let old_value = mem::replace(&mut branch.value, Some(value));
+                        let old_value = branch.value.replace(value);
                         let arena = &self.arena;
-                        return old_value.map(move |v| &arena[v]);
+                        return Ok(old_value.map(move |v| &arena[v]));
                     }
                 }
                 Some(Node::Extension(ref extension)) => {
@@ -263,6 +592,9 @@ impl Trie {
                     if let Some(p) = pos {
                         debug!("extension doesn't start with path nor path starts with extension");
                         break Action::Extension(extension.clone(), p as u32);
+                    } else if path.len() < extension.nibble.len() {
+                        debug!("extension starts with path");
+                        break Action::Extension(extension.clone(), path.len());
                     } else {
                         debug!(
                             "path {} starts with extension {}",
@@ -270,6 +602,7 @@ impl Trie {
                             extension.nibble.len()
                         );
                         path = right.unwrap_or_default();
+                        parent = Some((key, None));
                         key = extension.key;
                     }
                 }
@@ -289,7 +622,7 @@ impl Trie {
                     } else if path.len() == leaf.nibble.len() {
                         debug!("nibble == leaf => replace leaf");
                         let old_val = mem::replace(&mut leaf.value, value);
-                        return Some(&self.arena[old_val]);
+                        return Ok(Some(&self.arena[old_val]));
                     } else {
                         debug!("leaf starts with path");
                         break Action::Leaf(leaf.clone(), path.len());
@@ -299,7 +632,8 @@ impl Trie {
             }
         };
 
-        self.execute_action(action, key, value, &path, arena)
+        self.execute_action(action, key, value, &path, arena)?;
+        Ok(None)
     }
 
     #[inline(always)]
@@ -310,7 +644,7 @@ impl Trie {
         value: usize,
         path: &Nibble,
         arena: &A,
-    ) -> Option<&[u8]>
+    ) -> Result<(), TrieError>
     where
         A: ::std::ops::Index<usize, Output = [u8]>,
     {
@@ -318,15 +652,19 @@ impl Trie {
         match action {
             Action::BranchKey(u, new_leaf) => {
                 let new_key = self.db.push_node(Node::Leaf(new_leaf));
-                if let Node::Branch(ref mut branch) = self.db.get_mut(&mut key)? {
-                    branch.keys[u as usize] = Some(new_key);
+                match self.db.get_mut(&mut key) {
+                    Some(Node::Branch(ref mut branch)) => branch.keys[u as usize] = Some(new_key),
+                    _ => return Err(TrieError::Corrupted("expecting a branch")),
                 }
             }
             Action::Extension(ext, offset) => {
-                self.db.remove(&key);
-
                 let (_, path) = path.split_at(offset);
                 let (ext_left, ext_right) = ext.nibble.split_at(offset);
+                let (ext_u, ext_nibble) = ext_right
+                    .and_then(|n| n.pop_front(&self.arena))
+                    .ok_or(TrieError::Corrupted("extension nibble too short"))?;
+
+                self.db.remove(&key);
 
                 let mut branch = Branch::default();
 
@@ -338,22 +676,18 @@ impl Trie {
                     branch.value = Some(value);
                 }
 
-                if let Some((u, nibble)) = ext_right.and_then(|n| n.pop_front(&self.arena)) {
-                    let new_key = if nibble.len() == 0 {
-                        // there is no nibble extension so the extension is useless
-                        // and we can directly refer to the nibble key
-                        ext.key
-                    } else {
-                        let ext = Extension {
-                            nibble,
-                            key: ext.key,
-                        };
-                        self.db.push_node(Node::Extension(ext))
-                    };
-                    branch.keys[u as usize] = Some(new_key);
+                let new_key = if ext_nibble.is_empty() {
+                    // there is no nibble extension so the extension is useless
+                    // and we can directly refer to the nibble key
+                    ext.key
                 } else {
-                    panic!("extension nibble too short");
-                }
+                    let ext = Extension {
+                        nibble: ext_nibble,
+                        key: ext.key,
+                    };
+                    self.db.push_node(Node::Extension(ext))
+                };
+                branch.keys[ext_u as usize] = Some(new_key);
 
                 if offset > 0 {
                     let branch_key = self.db.push_node(Node::Branch(Box::new(branch)));
@@ -409,91 +743,674 @@ impl Trie {
                 self.db.insert_node(key, Node::Leaf(Leaf { nibble, value }));
             }
         }
-        None
-    }
-
-    pub fn commit(&mut self) {
-        self.db.commit(&mut self.arena);
+        Ok(())
     }
 
-    pub fn commit_into<'a, F, E>(&'a mut self, insert: F) -> Result<(), E>
-    where
-        F: Fn(&[(&'a [u8], &'a [u8])]) -> Result<(), E>,
-    {
-        let new_hashes = self.db.commit(&mut self.arena);
-        let arena = &self.arena;
-        let key_values = new_hashes
-            .into_iter()
-            .map(|(hash_idx, encoded_idx)| (&arena[hash_idx], &arena[encoded_idx]))
-            .collect::<Vec<_>>();
-
-        insert(&key_values)
-    }
+    /// Remove the value at this key and returns it, if any
+    pub fn remove<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<&[u8]>, TrieError> {
+        self.apply_budget();
+        let data = key.as_ref();
+        let nibble = Nibble {
+            data: 0,
+            start: 0,
+            end: data.len() as u32 * 2,
+        };
+        let data = &[data];
+        let arena = &ArenaSlice(data.as_ref());
 
-    pub fn iter(&self) -> DFSIter {
-        DFSIter::new(self)
-    }
-}
+        // do not move any node into memory if there is nothing to remove
+        if self.lookup(&nibble, arena)?.is_none() {
+            return Ok(None);
+        }
+        // load everything we may need beforehand so the trie is never left half modified
+        self.load_siblings(nibble.clone(), arena)?;
 
-impl Drop for Trie {
-    fn drop(&mut self) {
-        self.commit();
+        let mut key = self.db.root_index();
+        let value = self.remove_nibble(&mut key, nibble, arena)?;
+        Ok(value.map(move |v| &self.arena[v]))
     }
-}
-
-#[cfg(test)]
-mod test {
-
-    use super::*;
-    use db::Index;
-    use keccak_hash::KECCAK_NULL_RLP;
-    use keccak_hasher::KeccakHasher;
-    use std::str::from_utf8;
-    use std::sync::{Once, ONCE_INIT};
-    use triehash::trie_root;
-
-    static INIT: Once = ONCE_INIT;
 
-    /// Setup function that is only run once, even if called multiple times.
-    fn setup() {
-        INIT.call_once(|| {
-            ::env_logger::init();
-        });
+    /// Load the nodes branches on `path` could collapse into if the item at `path` is removed
+    fn load_siblings<A>(&mut self, mut path: Nibble, arena: &A) -> Result<(), TrieError>
+    where
+        A: ::std::ops::Index<usize, Output = [u8]>,
+    {
+        let mut siblings = Vec::new();
+        let mut key = self.db.root_index();
+        loop {
+            match self.db.get(&key) {
+                Some(Node::Branch(ref branch)) => {
+                    let next = path.pop_front(arena);
+                    let on_path = next.as_ref().map(|&(u, _)| u as usize);
+                    let children = branch.keys.iter().flatten().count();
+                    if children + branch.value.iter().count() == 2 {
+                        siblings.extend(
+                            branch
+                                .keys
+                                .iter()
+                                .enumerate()
+                                .filter(|&(u, _)| Some(u) != on_path)
+                                .filter_map(|(_, k)| *k),
+                        );
+                    }
+                    match next.and_then(|(u, n)| branch.keys[u as usize].map(|k| (k, n))) {
+                        Some((k, n)) => {
+                            key = k;
+                            path = n;
+                        }
+                        None => break,
+                    }
+                }
+                Some(Node::Extension(ref extension)) => {
+                    let (_, right) = path.split_at(extension.nibble.len());
+                    path = right.unwrap_or_default();
+                    key = extension.key;
+                }
+                _ => break,
+            }
+        }
+        for sibling in siblings {
+            self.load(sibling)?;
+        }
+        Ok(())
     }
 
-    // we use a macro here so the failing test shows where the macro is called instead
-    // of the assert_eq line
-    macro_rules! node_eq {
-        ($trie:expr, $inputs:expr) => {
-            for (i, &(key, value)) in $inputs.iter().enumerate() {
-                let v = $trie.get(key);
-                assert_eq!(
-                    v,
-                    Some(value.as_bytes()),
-                    "leaf {}: {:?} / {:?}\ntrie: {:?}",
-                    i,
-                    key,
-                    value,
-                    $trie
-                );
+    /// Remove the item at `path` below node `key`
+    ///
+    /// Nodes are restructured on the way back so the trie stays canonical:
+    /// - a branch with a single item is replaced by a leaf or an extension
+    /// - an extension pointing to a leaf or an extension is merged with it
+    fn remove_nibble<A>(
+        &mut self,
+        key: &mut Index,
+        path: Nibble,
+        arena: &A,
+    ) -> Result<Option<usize>, TrieError>
+    where
+        A: ::std::ops::Index<usize, Output = [u8]>,
+    {
+        let node = match self.db.get_mut(key) {
+            Some(node) => mem::replace(node, Node::Empty),
+            None => return Err(TrieError::Corrupted("missing node on removal path")),
+        };
+        let (node, value) = match node {
+            Node::Leaf(leaf) => {
+                if leaf.nibble.eq(&path, &self.arena, arena) {
+                    (Node::Empty, Some(leaf.value))
+                } else {
+                    (Node::Leaf(leaf), None)
+                }
+            }
+            Node::Extension(mut extension) => {
+                let (left, right) = path.split_at(extension.nibble.len());
+                if extension.nibble.eq(&left, &self.arena, arena) {
+                    let path = right.unwrap_or_default();
+                    let value = self.remove_nibble(&mut extension.key, path, arena)?;
+                    (self.merge_extension(extension)?, value)
+                } else {
+                    (Node::Extension(extension), None)
+                }
             }
+            Node::Branch(mut branch) => {
+                let value = match path.pop_front(arena) {
+                    Some((u, n)) => match branch.keys[u as usize] {
+                        Some(mut k) => {
+                            let value = self.remove_nibble(&mut k, n, arena)?;
+                            branch.keys[u as usize] = match self.db.get(&k) {
+                                Some(Node::Empty) => None,
+                                _ => Some(k),
+                            };
+                            value
+                        }
+                        None => None,
+                    },
+                    None => branch.value.take(),
+                };
+                (self.collapse_branch(branch)?, value)
+            }
+            Node::Empty => (Node::Empty, None),
         };
+        self.db.insert_node(*key, node);
+        Ok(value)
     }
 
-    #[test]
-    fn init() {
-        setup();
-        let mut trie = Trie::new();
-        assert_eq!(trie.root(), Some(KECCAK_NULL_RLP.as_ref()));
+    /// Remove all the items with a key between `left` and `right` (both included)
+    ///
+    /// Nodes entirely within the range are dropped without being loaded. Unlike `remove`
+    /// the trie is not restructured: it is meant to be filled back with the same items.
+    pub(crate) fn unset_range(&mut self, left: &[u8], right: &[u8]) -> Result<(), TrieError> {
+        let data = &[left, right];
+        let arena = &ArenaSlice(data.as_ref());
+        let left = Nibble {
+            data: 0,
+            start: 0,
+            end: left.len() as u32 * 2,
+        };
+        let right = Nibble {
+            data: 1,
+            start: 0,
+            end: right.len() as u32 * 2,
+        };
+        let mut key = self.db.root_index();
+        self.unset_nibbles(&mut key, Some(left), Some(right), arena)
     }
 
-    #[test]
-    fn insert_on_empty() {
-        setup();
+    /// Remove the items between `left` and `right` below node `key`
+    ///
+    /// A `None` bound means all the items below the node are on the inner side of it
+    fn unset_nibbles<A>(
+        &mut self,
+        key: &mut Index,
+        left: Option<Nibble>,
+        right: Option<Nibble>,
+        arena: &A,
+    ) -> Result<(), TrieError>
+    where
+        A: ::std::ops::Index<usize, Output = [u8]>,
+    {
+        self.load(*key)?;
+        let node = match self.db.get_mut(key) {
+            Some(node) => mem::replace(node, Node::Empty),
+            None => return Err(TrieError::Corrupted("missing node in range")),
+        };
+        let node = match node {
+            Node::Leaf(leaf) => {
+                let nibble = || leaf.nibble.iter(&self.arena);
+                let after_left =
+                    left.map_or(true, |l| nibble().cmp(l.iter(arena)) != Ordering::Less);
+                let before_right =
+                    right.map_or(true, |r| nibble().cmp(r.iter(arena)) != Ordering::Greater);
+                if after_left && before_right {
+                    Node::Empty
+                } else {
+                    Node::Leaf(leaf)
+                }
+            }
+            Node::Extension(mut extension) => {
+                let len = extension.nibble.len();
+                let (left_ord, right_ord) = {
+                    let cmp = |bound: &Nibble| {
+                        let bound = bound.iter(arena).take(len as usize);
+                        extension.nibble.iter(&self.arena).cmp(bound)
+                    };
+                    (
+                        left.as_ref().map_or(Ordering::Greater, &cmp),
+                        right.as_ref().map_or(Ordering::Less, &cmp),
+                    )
+                };
+                match (left_ord, right_ord) {
+                    (Ordering::Less, _) | (_, Ordering::Greater) => Node::Extension(extension),
+                    (Ordering::Greater, Ordering::Less) => Node::Empty,
+                    _ => {
+                        let inner = |bound: Option<Nibble>, ord| match ord {
+                            Ordering::Equal => bound.map(|b| b.split_at(len).1.unwrap_or_default()),
+                            _ => None,
+                        };
+                        let (left, right) = (inner(left, left_ord), inner(right, right_ord));
+                        self.unset_nibbles(&mut extension.key, left, right, arena)?;
+                        match self.db.get(&extension.key) {
+                            Some(Node::Empty) => Node::Empty,
+                            _ => Node::Extension(extension),
+                        }
+                    }
+                }
+            }
+            Node::Branch(mut branch) => {
+                // the branch value is never after `right`
+                let left = match left.map(|l| l.pop_front(arena)) {
+                    Some(Some(left)) => Some(left),
+                    _ => {
+                        branch.value = None;
+                        None
+                    }
+                };
+                // `Some(None)`: the branch is `right`, all its children are after it
+                let right = right.map(|r| r.pop_front(arena));
+                for u in 0..16u8 {
+                    let mut k = match branch.keys[u as usize] {
+                        Some(k) => k,
+                        None => continue,
+                    };
+                    let left = match left {
+                        Some((l, _)) if u < l => continue,
+                        Some((l, ref n)) if u == l => Some(n.clone()),
+                        _ => None,
+                    };
+                    let right = match right {
+                        Some(None) => continue,
+                        Some(Some((r, _))) if u > r => continue,
+                        Some(Some((r, ref n))) if u == r => Some(n.clone()),
+                        _ => None,
+                    };
+                    if left.is_none() && right.is_none() {
+                        branch.keys[u as usize] = None;
+                        continue;
+                    }
+                    self.unset_nibbles(&mut k, left, right, arena)?;
+                    branch.keys[u as usize] = match self.db.get(&k) {
+                        Some(Node::Empty) => None,
+                        _ => Some(k),
+                    };
+                }
+                if branch.value.is_none() && branch.keys.iter().all(Option::is_none) {
+                    Node::Empty
+                } else {
+                    Node::Branch(branch)
+                }
+            }
+            Node::Empty => Node::Empty,
+        };
+        self.db.insert_node(*key, node);
+        Ok(())
+    }
+
+    /// Replace a branch with a single item by an equivalent leaf or extension
+    fn collapse_branch(&mut self, branch: Box<Branch>) -> Result<Node, TrieError> {
+        let (u, key) = {
+            let mut keys = branch
+                .keys
+                .iter()
+                .enumerate()
+                .filter_map(|(u, k)| k.map(|k| (u as u8, k)));
+            match (keys.next(), keys.next(), branch.value) {
+                (None, None, Some(value)) => {
+                    let nibble = Nibble::from_nibbles(&[], &mut self.arena);
+                    return Ok(Node::Leaf(Leaf { nibble, value }));
+                }
+                (Some(child), None, None) => child,
+                _ => return Ok(Node::Branch(branch)),
+            }
+        };
+        debug!("collapsing branch into its child {}: {:?}", u, key);
+        self.merge_child(&[u], key)
+    }
+
+    /// Merge an extension with its child if it is not a branch
+    fn merge_extension(&mut self, extension: Extension) -> Result<Node, TrieError> {
+        match self.db.get(&extension.key) {
+            Some(Node::Leaf(_)) | Some(Node::Extension(_)) => {
+                let prefix = extension.nibble.iter(&self.arena).collect::<Vec<_>>();
+                self.merge_child(&prefix, extension.key)
+            }
+            Some(Node::Empty) => Ok(Node::Empty),
+            _ => Ok(Node::Extension(extension)),
+        }
+    }
+
+    /// Creates a node equivalent to the child at `key` prefixed by some nibbles
+    fn merge_child(&mut self, prefix: &[u8], key: Index) -> Result<Node, TrieError> {
+        self.load(key)?;
+        let (nibble, node) = match self.db.get(&key) {
+            Some(Node::Leaf(leaf)) => (&leaf.nibble, Node::Leaf(leaf.clone())),
+            Some(Node::Extension(ext)) => (&ext.nibble, Node::Extension(ext.clone())),
+            _ => {
+                let nibble = Nibble::from_nibbles(prefix, &mut self.arena);
+                return Ok(Node::Extension(Extension { nibble, key }));
+            }
+        };
+        let mut nibbles = prefix.to_vec();
+        nibbles.extend(nibble.iter(&self.arena));
+        let nibble = Nibble::from_nibbles(&nibbles, &mut self.arena);
+        self.db.remove(&key);
+        Ok(match node {
+            Node::Leaf(leaf) => Node::Leaf(Leaf { nibble, ..leaf }),
+            Node::Extension(ext) => Node::Extension(Extension { nibble, ..ext }),
+            node => node,
+        })
+    }
+
+    /// Takes a checkpoint and returns its id
+    ///
+    /// Checkpoints nest: reverting to or discarding one also drops the ones taken after it.
+    /// Nodes a checkpoint refers to are copied on write so reverting is immediate.
+    /// Checkpoints must be reverted to or discarded before committing, a trie dropped with open
+    /// checkpoints is not committed.
+    pub fn checkpoint(&mut self) -> usize {
+        self.db.checkpoint()
+    }
+
+    /// Discards all the changes made since checkpoint `id`
+    pub fn revert_to(&mut self, id: usize) -> Result<(), TrieError> {
+        self.db.revert_to(id)
+    }
+
+    /// Keeps the changes made since checkpoint `id`, which cannot be reverted to anymore
+    pub fn discard_checkpoint(&mut self, id: usize) -> Result<(), TrieError> {
+        self.db.discard_checkpoint(id)
+    }
+
+    /// Commit all memory nodes, new nodes are written into the store
+    ///
+    /// Returns the new nodes and the nodes dereferenced since last commit. Fails with
+    /// `PendingCheckpoints` if checkpoints are open, as do all the methods committing first
+    /// (`root`, proofs, `fork`, `diff`). If the store fails, the trie is left uncommitted
+    /// and the next commit writes the nodes again.
+    pub fn commit(&mut self) -> Result<Changeset<'_>, TrieError> {
+        let checkpoints = self.db.checkpoints();
+        if checkpoints > 0 {
+            return Err(TrieError::PendingCheckpoints(checkpoints));
+        }
+        self.apply_budget();
+        let mut undo = self.db.uncommitted();
+        let dereferenced = self.db.take_dereferenced::<H>(&self.arena);
+        let new_hashes = self.db.commit::<H>(&mut self.arena, &mut undo);
+        // an empty commit is not a new version of the trie
+        if !new_hashes.is_empty() || !dereferenced.is_empty() {
+            let arena = &self.arena;
+            let inserted = new_hashes
+                .iter()
+                .map(|&(hash_idx, encoded_idx)| (&arena[hash_idx], &arena[encoded_idx]))
+                .collect::<Vec<_>>();
+            if let Err(e) = self.store.commit(&inserted, &dereferenced) {
+                // the changes are committed again on next call
+                self.db
+                    .revert_commit(&mut self.arena, undo, &new_hashes, &dereferenced);
+                return Err(e);
+            }
+        }
+        let arena = &self.arena;
+        let inserted = new_hashes
+            .into_iter()
+            .map(|(hash_idx, encoded_idx)| (&arena[hash_idx], &arena[encoded_idx]))
+            .collect::<Vec<_>>();
+        // evicting now would invalidate the changeset
+        let usage = self.memory_usage();
+        self.over_budget = self.budget.map_or(false, |budget| usage > budget);
+        Ok(Changeset {
+            inserted,
+            dereferenced,
+        })
+    }
+
+    /// Reclaims the arena memory used by superseded values, nodes and hashes
+    ///
+    /// Only the data reachable from the live nodes is kept, uncommitted nodes included.
+    /// The nodes shared with forks are copied. Fails with `PendingCheckpoints` if checkpoints
+    /// are open.
+    pub fn compact(&mut self) -> Result<(), TrieError> {
+        let checkpoints = self.db.checkpoints();
+        if checkpoints > 0 {
+            return Err(TrieError::PendingCheckpoints(checkpoints));
+        }
+        self.db.compact(&mut self.arena);
+        Ok(())
+    }
+
+    /// Commit all memory nodes and write the changes into the store and into `store`
+    pub fn commit_into<T: NodeStore>(&mut self, store: &mut T) -> Result<(), TrieError> {
+        let changes = self.commit()?;
+        store.commit(&changes.inserted, &changes.dereferenced)
+    }
+
+    /// Compares this trie with the trie at `old_root`, read from the same store
+    ///
+    /// Returns the changes from `old_root` to this trie, in lexicographic key order.
+    /// Subtries with the same hash are skipped without being loaded. Pending changes are
+    /// committed first.
+    pub fn diff(&mut self, old_root: &[u8]) -> Result<Vec<Change>, TrieError> {
+        self.commit()?;
+        let mut arena = Arena::new();
+        let mut db = Db::new::<H>(&mut arena);
+        if old_root != H::null_node().as_ref() {
+            let root = arena.push(old_root);
+            db.set_root(Index::Hash(root));
+        }
+        let new = Side {
+            db: &mut self.db,
+            arena: &mut self.arena,
+            store: &self.store,
+        };
+        let old = Side {
+            db: &mut db,
+            arena: &mut arena,
+            store: &self.store,
+        };
+        diff::diff::<H, _, _>(new, old)
+    }
+
+    /// Compares this trie with `old`, see `Trie::diff`
+    pub fn diff_trie<T: NodeStore>(
+        &mut self,
+        old: &mut Trie<T, H>,
+    ) -> Result<Vec<Change>, TrieError> {
+        self.commit()?;
+        old.commit()?;
+        let new = Side {
+            db: &mut self.db,
+            arena: &mut self.arena,
+            store: &self.store,
+        };
+        let old = Side {
+            db: &mut old.db,
+            arena: &mut old.arena,
+            store: &old.store,
+        };
+        diff::diff::<H, _, _>(new, old)
+    }
+
+    /// Creates a mutable copy of the trie, sharing all its nodes
+    ///
+    /// Pending changes are committed first. The committed nodes are then frozen and shared,
+    /// without any copy, by this trie and the fork: each one copies the nodes it modifies.
+    /// Both tries are independent afterwards, they can be sent to different threads.
+    ///
+    /// The fork gets a clone of the store, a handle to the same backend, e.g.
+    /// `Arc<RwLock<FileStore>>`. Unlike other tries, a fork dropped without being committed
+    /// discards its pending changes.
+    pub fn fork(&mut self) -> Result<Self, TrieError>
+    where
+        S: SharedStore,
+    {
+        self.commit()?;
+        self.db.share(&mut self.arena);
+        Ok(self.fork_shared())
+    }
+
+    /// Creates a fork, `share` must have been called since the last commit
+    fn fork_shared(&self) -> Self
+    where
+        S: SharedStore,
+    {
+        Trie {
+            arena: self.arena.fork(),
+            db: self.db.fork(),
+            store: self.store.clone(),
+            budget: self.budget,
+            over_budget: false,
+            commit_on_drop: false,
+            hasher: PhantomData,
+        }
+    }
+
+    /// Creates a read-only view of the current state of the trie, sharing all its nodes
+    ///
+    /// See `Trie::fork`
+    pub fn snapshot(&mut self) -> Result<Snapshot<S, H>, TrieError>
+    where
+        S: SharedStore,
+    {
+        Ok(Snapshot { trie: self.fork()? })
+    }
+
+    /// Iterates over all items, in lexicographic key order
+    ///
+    /// Memory nodes are iterated too, there is no need to commit first. Nodes are not
+    /// loaded from the store, see `iter_loading`.
+    pub fn iter(&self) -> DFSIter<'_> {
+        DFSIter::new(self)
+    }
+
+    /// Iterates over all items, in descending key order
+    pub fn iter_rev(&self) -> DFSIter<'_> {
+        self.iter().reversed()
+    }
+
+    /// Iterates over the items whose key is within `range`, in lexicographic order
+    pub fn range<K, R>(&self, range: R) -> RangeIter<'_>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let (start, end) = bounds(&range);
+        RangeIter::new(self, start, end)
+    }
+
+    /// Iterates over the items whose key starts with `prefix`
+    pub fn iter_prefix<P: AsRef<[u8]>>(&self, prefix: P) -> DFSIter<'_> {
+        DFSIter::with_prefix(self, prefix.as_ref())
+    }
+
+    /// Iterates over all items, in lexicographic key order, loading the nodes from the
+    /// store as the iteration reaches them
+    pub fn iter_loading(&mut self) -> LoadingIter<'_, S, H> {
+        self.apply_budget();
+        LoadingIter::new(self, false)
+    }
+
+    /// Iterates over all items, in descending key order, loading the nodes from the store
+    pub fn iter_rev_loading(&mut self) -> LoadingIter<'_, S, H> {
+        self.apply_budget();
+        LoadingIter::new(self, true)
+    }
+
+    /// Iterates over the items whose key is within `range`, in lexicographic order,
+    /// loading the nodes from the store
+    pub fn range_loading<K, R>(&mut self, range: R) -> Result<LoadingIter<'_, S, H>, TrieError>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        self.apply_budget();
+        let (start, end) = bounds(&range);
+        LoadingIter::range(self, start, end)
+    }
+
+    /// Iterates over the items whose key starts with `prefix`, loading the nodes from
+    /// the store
+    pub fn iter_prefix_loading<P: AsRef<[u8]>>(
+        &mut self,
+        prefix: P,
+    ) -> Result<LoadingIter<'_, S, H>, TrieError> {
+        self.apply_budget();
+        LoadingIter::with_prefix(self, prefix.as_ref())
+    }
+}
+
+impl Default for Trie {
+    fn default() -> Self {
+        Trie::new()
+    }
+}
+
+impl<S: SharedStore, H: Hasher> Snapshot<S, H> {
+    /// Get the root hash
+    pub fn root(&self) -> Result<&[u8], TrieError> {
+        self.trie
+            .db
+            .root(&self.trie.arena)
+            .ok_or(TrieError::Corrupted("uncommitted snapshot"))
+    }
+
+    /// Get the value of `key`
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>, TrieError> {
+        Ok(self.fork().get_loading(key)?.map(|value| value.to_vec()))
+    }
+
+    /// Get the merkle proof of `key`
+    pub fn get_proof<K: AsRef<[u8]>>(&self, key: K) -> Result<Vec<Vec<u8>>, TrieError> {
+        self.fork().get_proof(key)
+    }
+
+    /// Iterates over all items, in lexicographic key order
+    ///
+    /// All the items are read first.
+    pub fn iter(&self) -> Result<impl Iterator<Item = (Vec<u8>, Vec<u8>)>, TrieError> {
+        let items = self.fork().iter_loading().collect::<Result<Vec<_>, _>>()?;
+        Ok(items.into_iter())
+    }
+
+    /// Creates a mutable trie out of this snapshot, sharing all its nodes
+    pub fn fork(&self) -> Trie<S, H> {
+        self.trie.fork_shared()
+    }
+}
+
+impl<S: NodeStore, H: Hasher> Drop for Trie<S, H> {
+    fn drop(&mut self) {
+        if !self.commit_on_drop {
+            return;
+        }
+        // the state below an open checkpoint may be reverted yet, it is not committed
+        let checkpoints = self.db.checkpoints();
+        if checkpoints > 0 {
+            warn!(
+                "trie dropped with {} open checkpoints, not committed",
+                checkpoints
+            );
+            return;
+        }
+        if let Err(e) = self.commit() {
+            error!("cannot commit trie on drop: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use db::Index;
+    use keccak_hash::{keccak, KECCAK_NULL_RLP};
+    use keccak_hasher::KeccakHasher;
+    use std::collections::{BTreeMap, HashMap};
+    use std::str::from_utf8;
+    use std::sync::{Arc, Once, RwLock, ONCE_INIT};
+    use std::thread;
+    use testutil::{sample, Flaky, Short};
+    use triehash::trie_root;
+
+    static INIT: Once = ONCE_INIT;
+
+    /// Setup function that is only run once, even if called multiple times.
+    fn setup() {
+        INIT.call_once(|| {
+            ::env_logger::init();
+        });
+    }
+
+    // we use a macro here so the failing test shows where the macro is called instead
+    // of the assert_eq line
+    macro_rules! node_eq {
+        ($trie:expr, $inputs:expr) => {
+            for (i, &(key, value)) in $inputs.iter().enumerate() {
+                let v = $trie.get(key).unwrap();
+                assert_eq!(
+                    v,
+                    Some(value.as_bytes()),
+                    "leaf {}: {:?} / {:?}\ntrie: {:?}",
+                    i,
+                    key,
+                    value,
+                    $trie
+                );
+            }
+        };
+    }
+
+    #[test]
+    fn init() {
+        setup();
+        let mut trie = Trie::new();
+        assert_eq!(trie.root(), Ok(KECCAK_NULL_RLP.as_ref()));
+    }
+
+    #[test]
+    fn insert_on_empty() {
+        setup();
         let mut t = Trie::new();
 
-        t.insert(&[0x01u8, 0x23], &[0x01u8, 0x23]);
-        assert_eq!(t.get(&[0x01, 0x23]).unwrap(), &[0x01, 0x23]);
+        t.insert(&[0x01u8, 0x23], &[0x01u8, 0x23]).unwrap();
+        assert_eq!(t.get(&[0x01, 0x23]).unwrap().unwrap(), &[0x01, 0x23]);
 
         assert_eq!(
             t.root().unwrap(),
@@ -505,10 +1422,10 @@ mod test {
     fn insert_replace_root() {
         setup();
         let mut t = Trie::new();
-        t.insert(&[0x01u8, 0x23], &[0x01u8, 0x23]);
-        assert_eq!(t.get(&[0x01, 0x23]), Some([0x01, 0x23].as_ref()));
-        t.insert(&[0x01u8, 0x23], &[0x23u8, 0x45]);
-        assert_eq!(t.get(&[0x01, 0x23]), Some([0x23, 0x45].as_ref()));
+        t.insert(&[0x01u8, 0x23], &[0x01u8, 0x23]).unwrap();
+        assert_eq!(t.get(&[0x01, 0x23]).unwrap(), Some([0x01, 0x23].as_ref()));
+        t.insert(&[0x01u8, 0x23], &[0x23u8, 0x45]).unwrap();
+        assert_eq!(t.get(&[0x01, 0x23]).unwrap(), Some([0x23, 0x45].as_ref()));
         assert_eq!(
             t.root().unwrap(),
             &*trie_root::<KeccakHasher, _, _, _>(vec![(vec![0x01u8, 0x23], vec![0x23u8, 0x45])])
@@ -519,11 +1436,11 @@ mod test {
     fn insert_make_root() {
         setup();
         let mut t = Trie::new();
-        t.insert(&[0x01, 0x23], &[0x01]);
-        assert_eq!(t.get(&[0x01, 0x23]), Some([0x01].as_ref()));
-        t.insert(&[0x01], &[0x02]);
-        assert_eq!(t.get(&[0x01]), Some([0x02].as_ref()), "\n{:#?}", t);
-        assert_eq!(t.get(&[0x01, 0x23]), Some([0x01].as_ref()));
+        t.insert(&[0x01, 0x23], &[0x01]).unwrap();
+        assert_eq!(t.get(&[0x01, 0x23]).unwrap(), Some([0x01].as_ref()));
+        t.insert(&[0x01], &[0x02]).unwrap();
+        assert_eq!(t.get(&[0x01]).unwrap(), Some([0x02].as_ref()), "\n{:#?}", t);
+        assert_eq!(t.get(&[0x01, 0x23]).unwrap(), Some([0x01].as_ref()));
         assert_eq!(
             t.root().unwrap(),
             &*trie_root::<KeccakHasher, _, _, _>(vec![
@@ -537,9 +1454,9 @@ mod test {
     fn insert_make_branch_root() {
         setup();
         let mut t = Trie::new();
-        t.insert(&[0x01u8, 0x23], &[0x01u8, 0x23]);
-        assert_eq!(t.get(&[0x01, 0x23]).unwrap(), &[0x01, 0x23]);
-        t.insert(&[0x11u8, 0x23], &[0x11u8, 0x23]);
+        t.insert(&[0x01u8, 0x23], &[0x01u8, 0x23]).unwrap();
+        assert_eq!(t.get(&[0x01, 0x23]).unwrap().unwrap(), &[0x01, 0x23]);
+        t.insert(&[0x11u8, 0x23], &[0x11u8, 0x23]).unwrap();
         assert_eq!(
             t.root().unwrap(),
             &*trie_root::<KeccakHasher, _, _, _>(vec![
@@ -553,13 +1470,13 @@ mod test {
     fn insert_into_branch_root() {
         setup();
         let mut t = Trie::new();
-        t.insert(&[0x01u8, 0x23], &[0x01u8, 0x23]);
-        assert_eq!(t.get(&[0x01, 0x23]), Some([0x01, 0x23].as_ref()));
-        t.insert(&[0xf1u8, 0x23], &[0xf1u8, 0x23]);
-        assert_eq!(t.get(&[0xf1, 0x23]), Some([0xf1, 0x23].as_ref()));
-        t.insert(&[0x81u8, 0x23], &[0x81u8, 0x23]);
+        t.insert(&[0x01u8, 0x23], &[0x01u8, 0x23]).unwrap();
+        assert_eq!(t.get(&[0x01, 0x23]).unwrap(), Some([0x01, 0x23].as_ref()));
+        t.insert(&[0xf1u8, 0x23], &[0xf1u8, 0x23]).unwrap();
+        assert_eq!(t.get(&[0xf1, 0x23]).unwrap(), Some([0xf1, 0x23].as_ref()));
+        t.insert(&[0x81u8, 0x23], &[0x81u8, 0x23]).unwrap();
         assert_eq!(
-            t.get(&[0x81, 0x23]),
+            t.get(&[0x81, 0x23]).unwrap(),
             Some([0x81, 0x23].as_ref()),
             "\n{:?}",
             t
@@ -578,8 +1495,8 @@ mod test {
     fn insert_value_into_branch_root() {
         setup();
         let mut t = Trie::new();
-        t.insert(&[0x01u8, 0x23], &[0x01u8, 0x23]);
-        t.insert(&[], &[0x0]);
+        t.insert(&[0x01u8, 0x23], &[0x01u8, 0x23]).unwrap();
+        t.insert(&[], &[0x0]).unwrap();
         assert_eq!(
             t.root().unwrap(),
             &*trie_root::<KeccakHasher, _, _, _>(vec![
@@ -593,8 +1510,8 @@ mod test {
     fn insert_split_leaf() {
         setup();
         let mut t = Trie::new();
-        t.insert(&[0x01u8, 0x23], &[0x01u8, 0x23]);
-        t.insert(&[0x01u8, 0x34], &[0x01u8, 0x34]);
+        t.insert(&[0x01u8, 0x23], &[0x01u8, 0x23]).unwrap();
+        t.insert(&[0x01u8, 0x34], &[0x01u8, 0x34]).unwrap();
         assert_eq!(
             t.root().unwrap(),
             &*trie_root::<KeccakHasher, _, _, _>(vec![
@@ -608,14 +1525,14 @@ mod test {
     fn insert_split_extension() {
         setup();
         let mut t = Trie::new();
-        t.insert(&[0x01, 0x23, 0x45], &[0x01]);
-        assert_eq!(t.get(&[0x01, 0x23, 0x45]), Some([0x01].as_ref()));
-        t.insert(&[0x01, 0xf3, 0x45], &[0x02]);
-        assert_eq!(t.get(&[0x01, 0xf3, 0x45]), Some([0x02].as_ref()));
-        t.insert(&[0x01, 0xf3, 0xf5], &[0x03]);
-        assert_eq!(t.get(&[0x01, 0xf3, 0xf5]), Some([0x03].as_ref()));
-        t.insert(&[0x01, 0xf3], &[0x04]);
-        assert_eq!(t.get(&[0x01, 0xf3]), Some([0x04].as_ref()));
+        t.insert(&[0x01, 0x23, 0x45], &[0x01]).unwrap();
+        assert_eq!(t.get(&[0x01, 0x23, 0x45]).unwrap(), Some([0x01].as_ref()));
+        t.insert(&[0x01, 0xf3, 0x45], &[0x02]).unwrap();
+        assert_eq!(t.get(&[0x01, 0xf3, 0x45]).unwrap(), Some([0x02].as_ref()));
+        t.insert(&[0x01, 0xf3, 0xf5], &[0x03]).unwrap();
+        assert_eq!(t.get(&[0x01, 0xf3, 0xf5]).unwrap(), Some([0x03].as_ref()));
+        t.insert(&[0x01, 0xf3], &[0x04]).unwrap();
+        assert_eq!(t.get(&[0x01, 0xf3]).unwrap(), Some([0x04].as_ref()));
         assert_eq!(
             t.root().unwrap(),
             &*trie_root::<KeccakHasher, _, _, _>(vec![
@@ -634,8 +1551,8 @@ mod test {
 
         setup();
         let mut t = Trie::new();
-        t.insert(&[0x01u8, 0x23], big_value0);
-        t.insert(&[0x11u8, 0x23], big_value1);
+        t.insert(&[0x01u8, 0x23], big_value0).unwrap();
+        t.insert(&[0x11u8, 0x23], big_value1).unwrap();
         assert_eq!(
             t.root().unwrap(),
             &*trie_root::<KeccakHasher, _, _, _>(vec![
@@ -651,8 +1568,8 @@ mod test {
 
         setup();
         let mut t = Trie::new();
-        t.insert(&[0x01u8, 0x23], big_value);
-        t.insert(&[0x11u8, 0x23], big_value);
+        t.insert(&[0x01u8, 0x23], big_value).unwrap();
+        t.insert(&[0x11u8, 0x23], big_value).unwrap();
         assert_eq!(
             t.root().unwrap(),
             &*trie_root::<KeccakHasher, _, _, _>(vec![
@@ -666,35 +1583,35 @@ mod test {
     fn test_at_empty() {
         setup();
         let t = Trie::new();
-        assert_eq!(t.get(&[0x5]), None);
+        assert_eq!(t.get(&[0x5]).unwrap(), None);
     }
 
     #[test]
     fn test_at_one() {
         setup();
         let mut t = Trie::new();
-        t.insert(&[0x01u8, 0x23], &[0x01u8, 0x23]);
-        assert_eq!(t.get(&[0x1, 0x23]), Some([0x1u8, 0x23].as_ref()));
-        t.commit();
-        assert_eq!(t.get(&[0x1, 0x23]), Some([0x1u8, 0x23].as_ref()));
+        t.insert(&[0x01u8, 0x23], &[0x01u8, 0x23]).unwrap();
+        assert_eq!(t.get(&[0x1, 0x23]).unwrap(), Some([0x1u8, 0x23].as_ref()));
+        t.commit().unwrap();
+        assert_eq!(t.get(&[0x1, 0x23]).unwrap(), Some([0x1u8, 0x23].as_ref()));
     }
 
     #[test]
     fn test_at_three() {
         setup();
         let mut t = Trie::new();
-        t.insert(&[0x01u8, 0x23], &[0x01u8, 0x23]);
-        t.insert(&[0xf1u8, 0x23], &[0xf1u8, 0x23]);
-        t.insert(&[0x81u8, 0x23], &[0x81u8, 0x23]);
-        assert_eq!(t.get(&[0x01, 0x23]), Some([0x01u8, 0x23].as_ref()));
-        assert_eq!(t.get(&[0xf1, 0x23]), Some([0xf1u8, 0x23].as_ref()));
-        assert_eq!(t.get(&[0x81, 0x23]), Some([0x81u8, 0x23].as_ref()));
-        assert_eq!(t.get(&[0x82, 0x23]), None);
-        t.commit();
-        assert_eq!(t.get(&[0x01, 0x23]), Some([0x01u8, 0x23].as_ref()));
-        assert_eq!(t.get(&[0xf1, 0x23]), Some([0xf1u8, 0x23].as_ref()));
-        assert_eq!(t.get(&[0x81, 0x23]), Some([0x81u8, 0x23].as_ref()));
-        assert_eq!(t.get(&[0x82, 0x23]), None);
+        t.insert(&[0x01u8, 0x23], &[0x01u8, 0x23]).unwrap();
+        t.insert(&[0xf1u8, 0x23], &[0xf1u8, 0x23]).unwrap();
+        t.insert(&[0x81u8, 0x23], &[0x81u8, 0x23]).unwrap();
+        assert_eq!(t.get(&[0x01, 0x23]).unwrap(), Some([0x01u8, 0x23].as_ref()));
+        assert_eq!(t.get(&[0xf1, 0x23]).unwrap(), Some([0xf1u8, 0x23].as_ref()));
+        assert_eq!(t.get(&[0x81, 0x23]).unwrap(), Some([0x81u8, 0x23].as_ref()));
+        assert_eq!(t.get(&[0x82, 0x23]).unwrap(), None);
+        t.commit().unwrap();
+        assert_eq!(t.get(&[0x01, 0x23]).unwrap(), Some([0x01u8, 0x23].as_ref()));
+        assert_eq!(t.get(&[0xf1, 0x23]).unwrap(), Some([0xf1u8, 0x23].as_ref()));
+        assert_eq!(t.get(&[0x81, 0x23]).unwrap(), Some([0x81u8, 0x23].as_ref()));
+        assert_eq!(t.get(&[0x82, 0x23]).unwrap(), None);
     }
 
     #[test]
@@ -711,24 +1628,22 @@ mod test {
             ("test node 3", "my node long"),
         ];
 
-        trie.insert(&inputs[0].0, &inputs[0].1);
+        trie.insert(&inputs[0].0, &inputs[0].1).unwrap();
         node_eq!(&trie, &inputs[..1]);
 
-        trie.insert(&inputs[1].0, &inputs[1].1);
+        trie.insert(&inputs[1].0, &inputs[1].1).unwrap();
         node_eq!(&trie, &inputs[..2]);
 
-        trie.insert(&inputs[2].0, &inputs[2].1);
+        trie.insert(&inputs[2].0, &inputs[2].1).unwrap();
         node_eq!(&trie, &inputs[..3]);
 
         assert_eq!(
             trie.root(),
-            Some(
-                [
-                    239, 218, 198, 132, 179, 205, 251, 214, 82, 69, 141, 191, 115, 22, 225, 130, 4,
-                    14, 0, 46, 64, 110, 125, 69, 138, 52, 217, 145, 54, 236, 224, 233
-                ]
-                    .as_ref()
-            ),
+            Ok([
+                239, 218, 198, 132, 179, 205, 251, 214, 82, 69, 141, 191, 115, 22, 225, 130, 4, 14,
+                0, 46, 64, 110, 125, 69, 138, 52, 217, 145, 54, 236, 224, 233
+            ]
+            .as_ref()),
         );
 
         let items = trie.iter().collect::<Vec<_>>();
@@ -759,13 +1674,13 @@ mod test {
     fn import() {
         setup();
         let mut t = Trie::new();
-        t.insert(&[0x01u8, 0x23], &[0x01u8, 0x23]);
-        assert_eq!(t.get(&[0x01, 0x23]), Some([0x01, 0x23].as_ref()));
-        t.insert(&[0xf1u8, 0x23], &[0xf1u8, 0x23]);
-        assert_eq!(t.get(&[0xf1, 0x23]), Some([0xf1, 0x23].as_ref()));
-        t.insert(&[0x81u8, 0x23], &[0x81u8, 0x23]);
+        t.insert(&[0x01u8, 0x23], &[0x01u8, 0x23]).unwrap();
+        assert_eq!(t.get(&[0x01, 0x23]).unwrap(), Some([0x01, 0x23].as_ref()));
+        t.insert(&[0xf1u8, 0x23], &[0xf1u8, 0x23]).unwrap();
+        assert_eq!(t.get(&[0xf1, 0x23]).unwrap(), Some([0xf1, 0x23].as_ref()));
+        t.insert(&[0x81u8, 0x23], &[0x81u8, 0x23]).unwrap();
         assert_eq!(
-            t.get(&[0x81, 0x23]),
+            t.get(&[0x81, 0x23]).unwrap(),
             Some([0x81, 0x23].as_ref()),
             "\n{:?}",
             t
@@ -779,4 +1694,1266 @@ mod test {
             ])
         );
     }
+
+    #[test]
+    fn insert_after_commit() {
+        setup();
+        let items = sample(50);
+        let mut t = Trie::new();
+        for (i, (k, v)) in items.iter().enumerate() {
+            t.insert(k, v).unwrap();
+            if i % 7 == 0 {
+                t.commit().unwrap();
+            }
+        }
+        for (k, v) in &items {
+            assert_eq!(t.get(k).unwrap(), Some(v.as_ref()));
+        }
+        assert_eq!(
+            t.root().unwrap(),
+            &*trie_root::<KeccakHasher, _, _, _>(items)
+        );
+    }
+
+    #[test]
+    fn insert_prefix_of_extension() {
+        setup();
+        let mut t = Trie::new();
+        t.insert([0x01, 0x23, 0x45], [0x01]).unwrap();
+        t.insert([0x01, 0x23, 0x67], [0x02]).unwrap();
+        t.insert([0x01], [0x03]).unwrap();
+        assert_eq!(t.get([0x01]).unwrap(), Some([0x03].as_ref()));
+        assert_eq!(
+            t.root().unwrap(),
+            &*trie_root::<KeccakHasher, _, _, _>(vec![
+                (vec![0x01, 0x23, 0x45], vec![0x01]),
+                (vec![0x01, 0x23, 0x67], vec![0x02]),
+                (vec![0x01], vec![0x03]),
+            ])
+        );
+    }
+
+    #[test]
+    fn remove_root_leaf() {
+        setup();
+        let mut t = Trie::new();
+        t.insert([0x01u8, 0x23], [0x01u8, 0x23]).unwrap();
+        assert_eq!(
+            t.remove([0x01u8, 0x23]).unwrap(),
+            Some([0x01u8, 0x23].as_ref())
+        );
+        assert_eq!(t.get([0x01u8, 0x23]).unwrap(), None);
+        assert_eq!(t.root(), Ok(KECCAK_NULL_RLP.as_ref()));
+    }
+
+    #[test]
+    fn remove_missing() {
+        setup();
+        let mut t = Trie::new();
+        t.insert([0x01u8, 0x23], [0x01u8, 0x23]).unwrap();
+        t.insert([0x01u8, 0x34], [0x01u8, 0x34]).unwrap();
+        let root = t.root().unwrap().to_vec();
+        assert_eq!(t.remove([0x01u8, 0x45]).unwrap(), None);
+        assert_eq!(t.remove([0x01u8]).unwrap(), None);
+        if let Index::Memory(_) = t.db.root_index() {
+            panic!("nothing should have been moved into memory");
+        }
+        assert_eq!(t.root().unwrap(), &*root);
+    }
+
+    #[test]
+    fn remove_collapse_branch() {
+        setup();
+        let mut t = Trie::new();
+        t.insert([0x01u8, 0x23], [0x01u8, 0x23]).unwrap();
+        t.insert([0x11u8, 0x23], [0x11u8, 0x23]).unwrap();
+        assert_eq!(
+            t.remove([0x11u8, 0x23]).unwrap(),
+            Some([0x11u8, 0x23].as_ref())
+        );
+        assert_eq!(
+            t.get([0x01u8, 0x23]).unwrap(),
+            Some([0x01u8, 0x23].as_ref())
+        );
+        assert_eq!(
+            t.root().unwrap(),
+            &*trie_root::<KeccakHasher, _, _, _>(vec![(vec![0x01u8, 0x23], vec![0x01u8, 0x23])])
+        );
+    }
+
+    #[test]
+    fn remove_branch_value() {
+        setup();
+        let mut t = Trie::new();
+        t.insert([0x01, 0x23], [0x01]).unwrap();
+        t.insert([0x01], [0x02]).unwrap();
+        t.insert([0x01, 0x23, 0x45], [0x03]).unwrap();
+        assert_eq!(t.remove([0x01]).unwrap(), Some([0x02].as_ref()));
+        assert_eq!(t.remove([0x01, 0x23]).unwrap(), Some([0x01].as_ref()));
+        assert_eq!(
+            t.root().unwrap(),
+            &*trie_root::<KeccakHasher, _, _, _>(vec![(vec![0x01, 0x23, 0x45], vec![0x03])])
+        );
+    }
+
+    #[test]
+    fn remove_merge_extension() {
+        setup();
+        let mut t = Trie::new();
+        t.insert([0x01, 0x23, 0x45], [0x01]).unwrap();
+        t.insert([0x01, 0xf3, 0x45], [0x02]).unwrap();
+        t.insert([0x01, 0xf3, 0xf5], [0x03]).unwrap();
+        t.commit().unwrap();
+        assert_eq!(t.remove([0x01, 0x23, 0x45]).unwrap(), Some([0x01].as_ref()));
+        assert_eq!(
+            t.root().unwrap(),
+            &*trie_root::<KeccakHasher, _, _, _>(vec![
+                (vec![0x01, 0xf3, 0x45], vec![0x02]),
+                (vec![0x01, 0xf3, 0xf5], vec![0x03]),
+            ])
+        );
+        assert_eq!(t.remove([0x01, 0xf3, 0x45]).unwrap(), Some([0x02].as_ref()));
+        assert_eq!(
+            t.root().unwrap(),
+            &*trie_root::<KeccakHasher, _, _, _>(vec![(vec![0x01, 0xf3, 0xf5], vec![0x03])])
+        );
+    }
+
+    #[test]
+    fn remove_all() {
+        setup();
+        let mut items = sample(60);
+        let mut t = Trie::new();
+        for (k, v) in &items {
+            t.insert(k, v).unwrap();
+        }
+        t.commit().unwrap();
+        while !items.is_empty() {
+            let (k, v) = items.remove(items.len() / 3);
+            assert_eq!(t.remove(&k).unwrap(), Some(v.as_ref()));
+            assert_eq!(t.get(&k).unwrap(), None);
+            if items.len() % 4 == 0 {
+                assert_eq!(
+                    t.root().unwrap(),
+                    &*trie_root::<KeccakHasher, _, _, _>(items.clone())
+                );
+            }
+        }
+        assert_eq!(t.root(), Ok(KECCAK_NULL_RLP.as_ref()));
+    }
+
+    /// Checks that each proof node is referenced by its parent and that the first is the root
+    fn assert_linked(proof: &[Vec<u8>], root: &[u8]) {
+        assert_eq!(&*keccak(&proof[0]), root);
+        for nodes in proof.windows(2) {
+            let hash = keccak(&nodes[1]);
+            assert!(nodes[0].windows(Keccak::LENGTH).any(|h| h == &*hash));
+        }
+    }
+
+    #[test]
+    fn proof() {
+        setup();
+        let items = sample(50);
+        let mut t = Trie::new();
+        for (k, v) in &items {
+            t.insert(k, v).unwrap();
+        }
+        let root = t.root().unwrap().to_vec();
+        for (k, v) in &items {
+            let proof = t.get_proof(k).unwrap();
+            assert_linked(&proof, &root);
+            assert!(proof.last().unwrap().windows(v.len()).any(|w| w == &**v));
+        }
+
+        // absent keys are proven by the nodes up to the divergence
+        let proof = t.get_proof([0x01, 0x02, 0x03]).unwrap();
+        assert_linked(&proof, &root);
+    }
+
+    #[test]
+    fn proof_empty() {
+        setup();
+        let mut t = Trie::new();
+        let proof = t.get_proof([0x01]).unwrap();
+        assert_eq!(proof, vec![vec![0x80]]);
+        assert_linked(&proof, &KECCAK_NULL_RLP);
+    }
+
+    fn stored(items: &[(Vec<u8>, Vec<u8>)]) -> (HashMap<Vec<u8>, Vec<u8>>, Vec<u8>) {
+        let mut store = HashMap::new();
+        let root = {
+            let mut t = Trie::with_store(&mut store);
+            for (k, v) in items {
+                t.insert(k, v).unwrap();
+            }
+            t.root().unwrap().to_vec()
+        };
+        (store, root)
+    }
+
+    #[test]
+    fn commit_store() {
+        setup();
+        let items = sample(50);
+        let (store, root) = stored(&items);
+        assert!(store.contains_key(&root));
+        for (hash, encoded) in &store {
+            assert_eq!(&*keccak(encoded), &**hash);
+        }
+
+        let mut other = HashMap::new();
+        let mut t = Trie::new();
+        for (k, v) in &items {
+            t.insert(k, v).unwrap();
+        }
+        t.commit_into(&mut other).unwrap();
+        assert_eq!(store, other);
+    }
+
+    #[test]
+    fn lazy_load() {
+        setup();
+        let mut items = sample(50);
+        let (mut store, root) = stored(&items);
+
+        {
+            let mut t = Trie::from_root(&root, &mut store);
+            // nothing is loaded yet
+            assert_eq!(
+                t.get(&items[0].0),
+                Err(TrieError::MissingNode(root.to_vec()))
+            );
+            for (k, v) in &items {
+                assert_eq!(t.get_loading(k).unwrap(), Some(v.as_ref()));
+            }
+            assert_eq!(t.get([0x01, 0x02, 0x03]).unwrap(), None);
+        }
+
+        let root = {
+            let mut t = Trie::from_root(&root, &mut store);
+            let (k, v) = items.remove(7);
+            assert_eq!(t.remove(&k).unwrap(), Some(v.as_ref()));
+            t.insert([0x01, 0x02, 0x03], [0x04]).unwrap();
+            items.push((vec![0x01, 0x02, 0x03], vec![0x04]));
+            assert_eq!(
+                t.root().unwrap(),
+                &*trie_root::<KeccakHasher, _, _, _>(items.clone())
+            );
+            t.root().unwrap().to_vec()
+        };
+
+        // new nodes have been written into the store
+        let mut t = Trie::from_root(&root, &mut store);
+        for (k, v) in &items {
+            assert_eq!(t.get_loading(k).unwrap(), Some(v.as_ref()));
+        }
+    }
+
+    #[test]
+    fn import_store() {
+        setup();
+        let items = sample(50);
+        let (store, root) = stored(&items);
+
+        let mut t = Trie::from_root(&root, ());
+        match t.get(&items[0].0) {
+            Err(TrieError::MissingNode(hash)) => assert_eq!(hash, root),
+            r => panic!("expecting missing root, got {:?}", r),
+        }
+        t.import(&store).unwrap();
+        for (k, v) in &items {
+            assert_eq!(t.get(k).unwrap(), Some(v.as_ref()));
+        }
+
+        // nodes out of the prefix are not imported, unless inlined into nodes on the path
+        let mut t = Trie::from_root(&root, ());
+        let prefix = items[3].0[0];
+        t.import_prefix(&store, &[prefix]).unwrap();
+        let mut missing = 0;
+        for (k, v) in &items {
+            match t.get(k) {
+                Ok(Some(value)) => assert_eq!(value, &**v),
+                Err(TrieError::MissingNode(_)) if k[0] != prefix => missing += 1,
+                r => panic!("cannot find key {:?}: {:?}", k, r),
+            }
+        }
+        assert!(missing > 0);
+    }
+
+    #[test]
+    fn from_root() {
+        setup();
+        let items = sample(50);
+        let (mut store, root) = stored(&items);
+
+        // nothing is loaded until needed
+        let mut t = Trie::from_root(&root, &mut store);
+        assert_eq!(t.db.root_index(), Index::Hash(2));
+        assert!(!t.db.contains(2));
+        assert_eq!(t.root().unwrap(), &*root);
+        for (k, v) in &items {
+            assert_eq!(t.get_loading(k).unwrap(), Some(v.as_ref()));
+        }
+    }
+
+    #[test]
+    fn from_empty_root() {
+        setup();
+        let mut t = Trie::from_root(&KECCAK_NULL_RLP, HashMap::new());
+        assert_eq!(t.get([0x01]).unwrap(), None);
+        t.insert([0x01], [0x02]).unwrap();
+        assert_eq!(
+            t.root().unwrap(),
+            &*trie_root::<KeccakHasher, _, _, _>(vec![(vec![0x01], vec![0x02])])
+        );
+    }
+
+    #[test]
+    fn missing_node() {
+        setup();
+        let items = sample(50);
+        let (mut store, root) = stored(&items);
+        let (k, _) = &items[5];
+        let proof = Trie::from_root(&root, &mut store).get_proof(k).unwrap();
+        let hash = keccak(proof.last().unwrap());
+        store.remove(&*hash);
+
+        let mut t = Trie::from_root(&root, &mut store);
+        assert_eq!(t.get_loading(k), Err(TrieError::MissingNode(hash.to_vec())));
+        assert_eq!(t.remove(k), Err(TrieError::MissingNode(hash.to_vec())));
+        assert_eq!(t.root().unwrap(), &*root);
+    }
+
+    #[test]
+    fn invalid_node() {
+        setup();
+        let mut store = HashMap::new();
+        let leaf = [0xc4, 0x82, 0x40, 0x01, 0x02];
+        store.insert(keccak(leaf).to_vec(), leaf.to_vec());
+        let mut t = Trie::from_root(&keccak(leaf), store);
+        assert_eq!(
+            t.get_loading([0x01]),
+            Err(TrieError::InvalidNibblePrefix(0x40))
+        );
+
+        let mut store = HashMap::new();
+        store.insert(keccak([0xff]).to_vec(), vec![0xff]);
+        let mut t = Trie::from_root(&keccak([0xff]), store);
+        match t.get_loading([0x01]) {
+            Err(TrieError::InvalidRlp(_)) => (),
+            r => panic!("expecting invalid rlp, got {:?}", r),
+        }
+    }
+
+    struct ReadOnly;
+
+    impl NodeStore for ReadOnly {
+        fn get(&self, _hash: &[u8]) -> Result<Option<Vec<u8>>, TrieError> {
+            Ok(None)
+        }
+
+        fn put(&mut self, _hash: &[u8], _encoded: &[u8]) -> Result<(), TrieError> {
+            Err(TrieError::Store("read only".to_string()))
+        }
+    }
+
+    #[test]
+    fn store_error() {
+        setup();
+        let mut t = Trie::with_store(ReadOnly);
+        t.insert([0x01], [0x02]).unwrap();
+        assert_eq!(t.commit(), Err(TrieError::Store("read only".to_string())));
+    }
+
+    #[test]
+    fn store_error_retry() {
+        setup();
+        let mut items = sample(50);
+        let mut t = Trie::with_store(Flaky::default());
+        for (k, v) in &items {
+            t.insert(k, v).unwrap();
+        }
+        let error = Err(TrieError::Store("flaky".to_string()));
+        assert_eq!(t.commit(), error);
+        assert!(t.store().nodes.is_empty());
+
+        // the failed commit is written again
+        let changes = t.commit().unwrap();
+        assert!(!changes.inserted.is_empty());
+        let root = t.root().unwrap().to_vec();
+        assert_eq!(root, &*trie_root::<KeccakHasher, _, _, _>(items.clone()));
+
+        for (i, (k, v)) in items.iter_mut().enumerate() {
+            if i % 3 == 0 {
+                *v = vec![0x42; 1 + i % 50];
+                t.insert(k, &v).unwrap();
+            }
+        }
+        assert_eq!(t.commit(), error);
+        let changes = t.commit().unwrap();
+        assert!(!changes.dereferenced.is_empty());
+        let root = t.root().unwrap().to_vec();
+        assert_eq!(root, &*trie_root::<KeccakHasher, _, _, _>(items.clone()));
+
+        let mut reopened = Trie::from_root(&root, t.store().nodes.clone());
+        for (k, v) in &items {
+            assert_eq!(reopened.get_loading(k).unwrap(), Some(&**v));
+        }
+    }
+
+    #[test]
+    fn iter_prefix() {
+        setup();
+        let mut items = sample(50);
+        items.push((vec![0x01], vec![0x01]));
+        items.push((vec![0x01, 0x23, 0x45], vec![0x02]));
+        items.push((vec![0x01, 0x23, 0x67], vec![0x03]));
+        items.push((vec![0xd0, 0x00, 0x01], vec![0x04]));
+        items.push((vec![0xd0, 0x00, 0x02], vec![0x05]));
+        let mut t = Trie::new();
+        for (k, v) in &items {
+            t.insert(k, v).unwrap();
+        }
+        t.commit().unwrap();
+
+        let prefixes: &[&[u8]] = &[
+            &[],
+            &[0x01],
+            &[0x01, 0x23],
+            &[0x01, 0x20],
+            &[0x94],
+            &[0x94, 0x00],
+            &[0xd0],
+            &[0xd0, 0x00],
+            &[0xff],
+        ];
+        for prefix in prefixes {
+            let mut expected = items
+                .iter()
+                .filter(|(k, _)| k.starts_with(prefix))
+                .cloned()
+                .collect::<Vec<_>>();
+            expected.sort();
+            let mut found = t
+                .iter_prefix(prefix)
+                .map(|(k, v)| (k.to_vec(), v.to_vec()))
+                .collect::<Vec<_>>();
+            found.sort();
+            assert_eq!(found, expected, "prefix {:?}", prefix);
+        }
+    }
+
+    #[test]
+    fn seek() {
+        setup();
+        let mut items = sample(50);
+        items.push((vec![0x01], vec![0x01]));
+        items.push((vec![0x01, 0x23, 0x45], vec![0x02]));
+        items.push((vec![0xd0, 0x00, 0x01], vec![0x04]));
+        items.push((vec![0xd0, 0x00, 0x02], vec![0x05]));
+        let mut t = Trie::new();
+        for (k, v) in &items {
+            t.insert(k, v).unwrap();
+        }
+        t.commit().unwrap();
+        items.sort();
+
+        let all = t
+            .iter()
+            .map(|(k, v)| (k.to_vec(), v.to_vec()))
+            .collect::<Vec<_>>();
+        assert_eq!(all, items);
+
+        let keys: &[&[u8]] = &[
+            &[],
+            &[0x00],
+            &[0x01],
+            &[0x01, 0x23],
+            &[0x01, 0x23, 0x45, 0x00],
+            &[0x94, 0x04, 0x04],
+            &[0x94, 0x04, 0x05],
+            &[0xd0],
+            &[0xd0, 0x00, 0x01, 0x00],
+            &[0xff],
+        ];
+        for key in keys {
+            let mut iter = t.iter();
+            iter.seek(key);
+            let found = iter
+                .map(|(k, v)| (k.to_vec(), v.to_vec()))
+                .collect::<Vec<_>>();
+            let expected = items
+                .iter()
+                .filter(|(k, _)| &**k >= *key)
+                .cloned()
+                .collect::<Vec<_>>();
+            assert_eq!(found, expected, "seek {:?}", key);
+        }
+
+        // seeking within a prefix stays under that prefix
+        let mut iter = t.iter_prefix([0xd0]);
+        iter.seek([0xd0, 0x00, 0x02]);
+        assert_eq!(
+            iter.map(|(k, _)| k.to_vec()).collect::<Vec<_>>(),
+            vec![vec![0xd0, 0x00, 0x02]]
+        );
+        let mut iter = t.iter_prefix([0xd0]);
+        iter.seek([0x01]);
+        assert_eq!(iter.count(), 2);
+        let mut iter = t.iter_prefix([0xd0]);
+        iter.seek([0xd1]);
+        assert_eq!(iter.count(), 0);
+    }
+
+    #[test]
+    fn range() {
+        setup();
+        let mut items = sample(50);
+        let mut t = Trie::new();
+        for (k, v) in &items {
+            t.insert(k, v).unwrap();
+        }
+        t.commit().unwrap();
+        items.sort();
+
+        let collect = |iter: RangeIter| iter.map(|(k, _)| k.to_vec()).collect::<Vec<_>>();
+        let keys = |f: &dyn Fn(&[u8]) -> bool| {
+            items
+                .iter()
+                .map(|(k, _)| k.clone())
+                .filter(|k| f(k))
+                .collect::<Vec<_>>()
+        };
+        let (a, b) = (items[10].0.clone(), items[20].0.clone());
+
+        assert_eq!(
+            collect(t.range(a.clone()..b.clone())),
+            keys(&|k| k >= &*a && k < &*b)
+        );
+        assert_eq!(
+            collect(t.range(a.clone()..=b.clone())),
+            keys(&|k| k >= &*a && k <= &*b)
+        );
+        assert_eq!(collect(t.range(..b.clone())), keys(&|k| k < &*b));
+        assert_eq!(collect(t.range(a.clone()..)), keys(&|k| k >= &*a));
+        assert_eq!(
+            collect(t.range((Bound::Excluded(a.clone()), Bound::Unbounded))),
+            keys(&|k| k > &*a)
+        );
+        assert_eq!(
+            collect(t.range([0x40u8]..[0x80])),
+            keys(&|k| k >= &[0x40][..] && k < &[0x80][..])
+        );
+        assert_eq!(
+            collect(t.range(b.clone()..a.clone())),
+            Vec::<Vec<u8>>::new()
+        );
+    }
+
+    #[test]
+    fn iter_uncommitted() {
+        setup();
+        let mut items = sample(50);
+        let mut t = Trie::new();
+        for (k, v) in &items[..20] {
+            t.insert(k, v).unwrap();
+        }
+        let mut sorted = items[..20].to_vec();
+        sorted.sort();
+        let all = t
+            .iter()
+            .map(|(k, v)| (k.to_vec(), v.to_vec()))
+            .collect::<Vec<_>>();
+        assert_eq!(all, sorted);
+
+        // mix of committed and memory nodes
+        t.commit().unwrap();
+        for (k, v) in &items[20..] {
+            t.insert(k, v).unwrap();
+        }
+        let (k, _) = items.remove(3);
+        t.remove(&k).unwrap();
+        items.sort();
+        assert!(t.iter().map(|(k, v)| (k.to_vec(), v.to_vec())).eq(items));
+    }
+
+    #[test]
+    fn iter_lazy() {
+        setup();
+        let mut items = sample(100);
+        let mut store = HashMap::new();
+        let root = {
+            let mut t = Trie::with_store(&mut store);
+            for (k, v) in &items {
+                t.insert(k, v).unwrap();
+            }
+            t.root().unwrap().to_vec()
+        };
+        items.sort();
+
+        // nodes are loaded from the store as the iteration reaches them
+        let mut t = Trie::from_root(&root, store.clone());
+        assert!(t.iter().next().is_none());
+        assert!(t.iter_loading().map(|item| item.unwrap()).eq(items.clone()));
+        assert!(t
+            .iter()
+            .map(|(k, v)| (k.to_vec(), v.to_vec()))
+            .eq(items.clone()));
+
+        let mut t = Trie::from_root(&root, store.clone());
+        let mut rev = items.clone();
+        rev.reverse();
+        assert!(t.iter_rev_loading().map(|item| item.unwrap()).eq(rev));
+
+        // only the nodes iterated so far are loaded
+        let mut t = Trie::from_root(&root, store.clone());
+        assert_eq!(t.iter_loading().take(3).count(), 3);
+        let loaded = t.db().loaded();
+        assert!(loaded < 10, "{} nodes loaded", loaded);
+
+        let (a, b) = (items[10].0.clone(), items[20].0.clone());
+        let mut t = Trie::from_root(&root, store.clone());
+        assert_eq!(t.range_loading(a..b).unwrap().count(), 10);
+        assert!(t.db().loaded() < items.len());
+        let mut t = Trie::from_root(&root, store.clone());
+        assert_eq!(
+            t.range_loading::<&[u8], _>(..).unwrap().count(),
+            items.len()
+        );
+
+        let mut t = Trie::from_root(&root, store.clone());
+        let prefix = &items[42].0[..1];
+        let expected = items.iter().filter(|(k, _)| k.starts_with(prefix)).count();
+        assert_eq!(t.iter_prefix_loading(prefix).unwrap().count(), expected);
+        assert_eq!(t.iter_prefix(prefix).count(), expected);
+
+        // a missing node is an error, not a truncated iteration
+        let mut t = Trie::from_root(&root, HashMap::new());
+        let mut iter = t.iter_loading();
+        match iter.next() {
+            Some(Err(TrieError::MissingNode(hash))) => assert_eq!(hash, root),
+            r => panic!("unexpected {:?}", r),
+        }
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn range_fused() {
+        setup();
+        let mut t = Trie::new();
+        for (k, v) in sample(50) {
+            t.insert(k, v).unwrap();
+        }
+        let mut iter = t.range(..[0x40u8]);
+        while iter.next().is_some() {}
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn iter_rev() {
+        setup();
+        let mut items = sample(50);
+        items.push((vec![0x01], vec![0x01]));
+        items.push((vec![0x01, 0x23, 0x45], vec![0x02]));
+        items.push((vec![0xd0, 0x00, 0x01], vec![0x04]));
+        items.push((vec![0xd0, 0x00, 0x02], vec![0x05]));
+        let mut t = Trie::new();
+        for (k, v) in &items {
+            t.insert(k, v).unwrap();
+        }
+        items.sort();
+        items.reverse();
+
+        let all = t
+            .iter_rev()
+            .map(|(k, v)| (k.to_vec(), v.to_vec()))
+            .collect::<Vec<_>>();
+        assert_eq!(all, items);
+
+        // last key under a prefix
+        let last = t
+            .iter_prefix([0xd0])
+            .reversed()
+            .next()
+            .map(|(k, _)| k.to_vec());
+        assert_eq!(last, Some(vec![0xd0, 0x00, 0x02]));
+        let last = t
+            .iter_prefix([0x01])
+            .reversed()
+            .next()
+            .map(|(k, _)| k.to_vec());
+        assert_eq!(last, Some(vec![0x01, 0x23, 0x45]));
+
+        let keys: &[&[u8]] = &[
+            &[],
+            &[0x00],
+            &[0x01],
+            &[0x01, 0x23],
+            &[0x01, 0x23, 0x45, 0x00],
+            &[0x94, 0x04, 0x04],
+            &[0x94, 0x04, 0x05],
+            &[0xd0],
+            &[0xd0, 0x00, 0x01, 0x00],
+            &[0xff],
+        ];
+        for key in keys {
+            let mut iter = t.iter_rev();
+            iter.seek(key);
+            let found = iter
+                .map(|(k, v)| (k.to_vec(), v.to_vec()))
+                .collect::<Vec<_>>();
+            let expected = items
+                .iter()
+                .filter(|(k, _)| &**k <= *key)
+                .cloned()
+                .collect::<Vec<_>>();
+            assert_eq!(found, expected, "seek {:?}", key);
+        }
+
+        let mut iter = t.iter_prefix([0xd0]).reversed();
+        iter.seek([0xd0, 0x00, 0x01, 0xff]);
+        assert_eq!(iter.count(), 1);
+        let mut iter = t.iter_prefix([0xd0]).reversed();
+        iter.seek([0xff]);
+        assert_eq!(iter.count(), 2);
+        let mut iter = t.iter_prefix([0xd0]).reversed();
+        iter.seek([0x01]);
+        assert_eq!(iter.count(), 0);
+    }
+
+    #[test]
+    fn from_proof() {
+        setup();
+        let mut items = sample(50);
+        let mut t = Trie::new();
+        for (k, v) in &items {
+            t.insert(k, v).unwrap();
+        }
+        let root = t.root().unwrap().to_vec();
+
+        let proven = [
+            items[3].0.clone(),
+            items[17].0.clone(),
+            vec![0x01, 0x02, 0x03],
+        ];
+        let proof = t.get_multiproof(&proven).unwrap();
+        let mut partial = Trie::from_proof(&root, &proof).unwrap();
+        assert_eq!(partial.root().unwrap(), &*root);
+        assert_eq!(partial.get(&items[3].0).unwrap(), Some(&*items[3].1));
+        assert_eq!(partial.get([0x01, 0x02, 0x03]).unwrap(), None);
+        match partial.get(&items[4].0) {
+            Err(TrieError::MissingNode(_)) => (),
+            r => panic!("expecting a missing node, got {:?}", r),
+        }
+
+        // apply the same writes to the full and the partial trie
+        partial.insert(&items[3].0, [0x42]).unwrap();
+        partial.insert(&items[17].0, [0x43; 40]).unwrap();
+        partial.insert([0x01, 0x02, 0x03], [0x44]).unwrap();
+        items[3].1 = vec![0x42];
+        items[17].1 = vec![0x43; 40];
+        items.push((vec![0x01, 0x02, 0x03], vec![0x44]));
+        assert_eq!(
+            partial.root().unwrap(),
+            &*trie_root::<KeccakHasher, _, _, _>(items)
+        );
+    }
+
+    #[test]
+    fn custom_hasher() {
+        setup();
+        let items = sample(50);
+        let mut store = HashMap::new();
+        let root = {
+            let mut t = Trie::<_, Short>::with_hasher(&mut store);
+            assert_eq!(t.root().unwrap(), &*Short::null_node());
+            for (k, v) in &items {
+                t.insert(k, v).unwrap();
+            }
+            t.root().unwrap().to_vec()
+        };
+        assert_eq!(root.len(), 20);
+        let keccak_root = trie_root::<KeccakHasher, _, _, _>(items.clone());
+        assert_ne!(&root[..], &keccak_root[..][..20]);
+        for (hash, node) in &store {
+            assert_eq!(hash, &Short::hash(node));
+        }
+
+        let mut t = Trie::<_, Short>::with_hasher(());
+        for (k, v) in items.iter().rev() {
+            t.insert(k, v).unwrap();
+        }
+        assert_eq!(t.root().unwrap(), &*root);
+
+        let mut t = Trie::<_, Short>::from_root_with_hasher(&root, &mut store);
+        for (k, v) in &items {
+            assert_eq!(t.get_loading(k).unwrap(), Some(&**v));
+        }
+        let proof = t.get_proof(&items[0].0).unwrap();
+        assert!(proof.iter().all(|node| node.len() >= 20));
+    }
+
+    #[test]
+    fn compact() {
+        setup();
+        let mut items = sample(100);
+        let mut t = Trie::new();
+        for round in 0..5u8 {
+            for (i, (k, v)) in items.iter_mut().enumerate() {
+                *v = vec![round; 1 + (i + round as usize) % 40];
+                t.insert(k, &v).unwrap();
+            }
+            t.commit().unwrap();
+        }
+        let root = t.root().unwrap().to_vec();
+        let size = t.arena.size();
+        t.compact().unwrap();
+        assert!(t.arena.size() < size / 3, "{} / {}", t.arena.size(), size);
+        assert_eq!(t.root().unwrap(), &*root);
+        for (k, v) in &items {
+            assert_eq!(t.get(k).unwrap(), Some(&**v));
+        }
+
+        // uncommitted changes are kept, so are the nodes they dereferenced
+        let mut reference = Trie::with_store(HashMap::new());
+        let mut t = Trie::with_store(HashMap::new());
+        for (k, v) in &items {
+            reference.insert(k, v).unwrap();
+            t.insert(k, v).unwrap();
+        }
+        reference.commit().unwrap();
+        t.commit().unwrap();
+        let (k, _) = items.remove(7);
+        reference.remove(&k).unwrap();
+        t.remove(&k).unwrap();
+        items[3].1 = vec![0x42; 3];
+        reference.insert(&items[3].0, &items[3].1).unwrap();
+        t.insert(&items[3].0, &items[3].1).unwrap();
+        // the copies kept by checkpoints would be lost
+        let id = t.checkpoint();
+        assert_eq!(t.compact(), Err(TrieError::PendingCheckpoints(1)));
+        t.discard_checkpoint(id).unwrap();
+        t.compact().unwrap();
+        assert_eq!(t.iter().count(), items.len());
+        assert_eq!(t.commit(), reference.commit());
+        let root = t.root().unwrap().to_vec();
+        assert_eq!(root, &*trie_root::<KeccakHasher, _, _, _>(items.clone()));
+
+        // unloaded nodes are kept as hashes
+        let mut t = Trie::from_root(&root, t.store().clone());
+        t.get_loading(&items[0].0).unwrap();
+        t.compact().unwrap();
+        t.insert(&items[1].0, [0x43]).unwrap();
+        items[1].1 = vec![0x43];
+        assert_eq!(
+            t.root().unwrap(),
+            &*trie_root::<KeccakHasher, _, _, _>(items)
+        );
+    }
+
+    #[test]
+    fn memory_budget() {
+        setup();
+        let budget = 8_000;
+        let mut items = sample(255);
+        let mut t = Trie::with_store(HashMap::new()).with_memory_budget(budget);
+        for (k, v) in &items {
+            t.insert(k, v).unwrap();
+        }
+        t.commit().unwrap();
+        let usage = t.memory_usage();
+        assert!(usage > budget, "{}", usage);
+
+        // nodes are evicted on next operation and loaded again when needed
+        assert_eq!(t.get_loading(&items[0].0).unwrap(), Some(&*items[0].1));
+        assert!(t.memory_usage() < budget, "{}", t.memory_usage());
+        assert!(t.db.loaded() < t.store().len());
+        let mut sorted = items.clone();
+        sorted.sort();
+        assert!(t.iter_loading().map(|item| item.unwrap()).eq(sorted));
+
+        for (i, (k, v)) in items.iter_mut().enumerate() {
+            if i % 3 == 0 {
+                *v = vec![0x42; i % 50];
+                t.insert(k, &v).unwrap();
+            }
+        }
+        let root = t.root().unwrap().to_vec();
+        assert_eq!(root, &*trie_root::<KeccakHasher, _, _, _>(items.clone()));
+        for (k, v) in &items {
+            assert_eq!(t.get_loading(k).unwrap(), Some(&**v));
+        }
+    }
+
+    #[test]
+    fn memory_budget_no_store() {
+        setup();
+        let items = sample(255);
+        let mut t = Trie::new().with_memory_budget(1_000);
+        for (k, v) in &items {
+            t.insert(k, v).unwrap();
+        }
+        t.commit().unwrap();
+        for (k, v) in &items {
+            assert_eq!(t.get(k).unwrap(), Some(&**v));
+        }
+        assert_eq!(t.iter().count(), items.len());
+    }
+
+    #[test]
+    fn checkpoint_commit() {
+        setup();
+        let items = sample(60);
+        let mut t = Trie::new();
+        let mut reference = Trie::new();
+        for (k, v) in &items[..30] {
+            t.insert(k, v).unwrap();
+            reference.insert(k, v).unwrap();
+        }
+        let root = t.root().unwrap().to_vec();
+        reference.commit().unwrap();
+
+        // committing doesn't drop the checkpoints
+        let id = t.checkpoint();
+        t.insert(&items[40].0, &items[40].1).unwrap();
+        assert_eq!(
+            t.get_proof(&items[40].0),
+            Err(TrieError::PendingCheckpoints(1))
+        );
+        t.revert_to(id).unwrap();
+        assert_eq!(t.get(&items[40].0).unwrap(), None);
+        assert_eq!(t.root().unwrap(), &*root);
+
+        // the copies of hashed nodes are released with the checkpoints
+        let id = t.checkpoint();
+        for (k, v) in &items[30..] {
+            t.insert(k, v).unwrap();
+            reference.insert(k, v).unwrap();
+        }
+        t.discard_checkpoint(id).unwrap();
+        t.commit().unwrap();
+        reference.commit().unwrap();
+        assert_eq!(t.db.loaded(), reference.db.loaded());
+    }
+
+    #[test]
+    fn checkpoint_drop() {
+        setup();
+        let items = sample(30);
+        let mut store = HashMap::new();
+        {
+            let mut t = Trie::with_store(&mut store);
+            for (k, v) in &items {
+                t.insert(k, v).unwrap();
+            }
+            t.checkpoint();
+        }
+        // the changes below an open checkpoint are not committed on drop
+        assert!(store.is_empty());
+
+        {
+            let mut t = Trie::with_store(&mut store);
+            for (k, v) in &items {
+                t.insert(k, v).unwrap();
+            }
+            let id = t.checkpoint();
+            t.discard_checkpoint(id).unwrap();
+        }
+        assert!(!store.is_empty());
+    }
+
+    #[test]
+    fn checkpoints() {
+        setup();
+        let items = sample(60);
+        let mut t = Trie::with_store(HashMap::new());
+        for (k, v) in &items[..30] {
+            t.insert(k, v).unwrap();
+        }
+        t.commit().unwrap();
+
+        // reverted changes are never committed
+        let id = t.checkpoint();
+        t.insert(&items[40].0, &items[40].1).unwrap();
+        t.remove(&items[0].0).unwrap();
+        t.revert_to(id).unwrap();
+        assert_eq!(t.commit(), Ok(Changeset::default()));
+        assert_eq!(t.revert_to(id), Err(TrieError::UnknownCheckpoint(id)));
+
+        // random operations against a model, with nested checkpoints
+        let mut model = items[..30].iter().cloned().collect::<BTreeMap<_, _>>();
+        let mut snapshots: Vec<BTreeMap<Vec<u8>, Vec<u8>>> = Vec::new();
+        let mut seed = 7u32;
+        for step in 0..600 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let r = (seed >> 8) as usize;
+            let (k, v) = &items[r % items.len()];
+            match r % 11 {
+                0..=3 => {
+                    t.insert(k, [v.as_slice(), &[step as u8]].concat()).unwrap();
+                    model.insert(k.clone(), [v.as_slice(), &[step as u8]].concat());
+                }
+                4..=5 => {
+                    t.remove(k).unwrap();
+                    model.remove(k);
+                }
+                6..=7 => {
+                    assert_eq!(t.checkpoint(), snapshots.len());
+                    snapshots.push(model.clone());
+                }
+                8 if !snapshots.is_empty() => {
+                    let id = r % snapshots.len();
+                    t.revert_to(id).unwrap();
+                    model = snapshots[id].clone();
+                    snapshots.truncate(id);
+                }
+                9 if !snapshots.is_empty() => {
+                    let id = r % snapshots.len();
+                    t.discard_checkpoint(id).unwrap();
+                    snapshots.truncate(id);
+                }
+                10 if r % 5 == 0 => {
+                    if !snapshots.is_empty() {
+                        assert_eq!(
+                            t.commit().map(|_| ()),
+                            Err(TrieError::PendingCheckpoints(snapshots.len()))
+                        );
+                        t.discard_checkpoint(0).unwrap();
+                        snapshots.clear();
+                    }
+                    t.commit().unwrap();
+                }
+                _ => (),
+            }
+            let found = t
+                .iter()
+                .map(|(k, v)| (k.to_vec(), v.to_vec()))
+                .collect::<BTreeMap<_, _>>();
+            assert_eq!(found, model, "step {}", step);
+        }
+        if !snapshots.is_empty() {
+            t.discard_checkpoint(0).unwrap();
+        }
+        assert_eq!(
+            t.root().unwrap(),
+            &*trie_root::<KeccakHasher, _, _, _>(model.clone())
+        );
+
+        // the store still has everything the current trie needs
+        let root = t.root().unwrap().to_vec();
+        let mut reopened = Trie::from_root(&root, t.store().clone());
+        for (k, v) in &model {
+            assert_eq!(reopened.get_loading(k).unwrap(), Some(&**v));
+        }
+    }
+
+    #[test]
+    fn forks() {
+        setup();
+        let items = sample(200);
+        let mut t = Trie::new();
+        for (k, v) in &items {
+            t.insert(k, v).unwrap();
+        }
+        let snapshot = t.snapshot().unwrap();
+        let root = snapshot.root().unwrap().to_vec();
+        assert_eq!(root, &*trie_root::<KeccakHasher, _, _, _>(items.clone()));
+
+        // candidate states computed concurrently on top of the same parent
+        let handles = (0..4u8)
+            .map(|n| {
+                let mut fork = t.fork().unwrap();
+                let mut items = items.clone();
+                thread::spawn(move || {
+                    for (i, (k, v)) in items.iter_mut().enumerate() {
+                        if i % 4 == n as usize {
+                            *v = vec![n; 1 + i % 50];
+                            fork.insert(k, &v).unwrap();
+                        }
+                    }
+                    let (k, _) = items.remove(n as usize * 10);
+                    fork.remove(&k).unwrap();
+                    (fork.root().unwrap().to_vec(), items)
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            let (fork_root, items) = handle.join().unwrap();
+            assert_eq!(fork_root, &*trie_root::<KeccakHasher, _, _, _>(items));
+        }
+
+        // the parent and the snapshot are independent
+        t.insert(&items[0].0, [0x42]).unwrap();
+        assert_ne!(t.root().unwrap(), &*root);
+        assert_eq!(snapshot.root().unwrap(), &*root);
+        for (k, v) in &items {
+            assert_eq!(snapshot.get(k).unwrap().as_ref(), Some(v));
+        }
+        assert_eq!(
+            ::proof::verify_proof(
+                &root,
+                &items[0].0,
+                &snapshot.get_proof(&items[0].0).unwrap()
+            ),
+            Ok(Some(items[0].1.clone()))
+        );
+        assert_eq!(snapshot.iter().unwrap().count(), items.len());
+
+        // nodes are shared, not copied
+        let mut fork = snapshot.fork();
+        assert_eq!(fork.memory_usage(), 0);
+        fork.insert(&items[0].0, [0x42]).unwrap();
+        assert_eq!(fork.root().unwrap(), t.root().unwrap());
+        t.compact().unwrap();
+        assert!(fork.memory_usage() < t.memory_usage() / 10);
+    }
+
+    #[test]
+    fn fork_chain() {
+        setup();
+        let mut items = sample(100);
+        let mut t = Trie::new();
+        for (k, v) in &items {
+            t.insert(k, v).unwrap();
+        }
+
+        // dropped forks leave a single base, with no stale node
+        for (i, (k, v)) in items.iter_mut().enumerate() {
+            let _ = t.fork().unwrap();
+            *v = vec![i as u8; 3];
+            t.insert(k, &v).unwrap();
+        }
+        t.commit().unwrap();
+        assert_eq!(t.db.depth(), 1);
+        assert_eq!(t.arena.depth(), 1);
+        let mut reference = Trie::new();
+        for (k, v) in &items {
+            reference.insert(k, v).unwrap();
+        }
+        reference.commit().unwrap();
+        let _ = t.fork().unwrap();
+        assert_eq!(t.db.loaded(), 0);
+        assert_eq!(t.db.loaded_with_shared(), reference.db.loaded());
+
+        // bases kept alive by forks are flattened
+        let mut forks = Vec::new();
+        for i in 0..20 {
+            forks.push((t.fork().unwrap(), items.clone()));
+            items[i].1 = vec![0x42];
+            t.insert(&items[i].0, &items[i].1).unwrap();
+        }
+        assert!(t.db.depth() <= ::arena::MAX_DEPTH);
+        assert!(t.arena.depth() <= ::arena::MAX_DEPTH);
+        assert_eq!(
+            t.root().unwrap(),
+            &*trie_root::<KeccakHasher, _, _, _>(items.clone())
+        );
+        for (fork, items) in &mut forks {
+            assert_eq!(
+                fork.root().unwrap(),
+                &*trie_root::<KeccakHasher, _, _, _>(items.clone())
+            );
+        }
+    }
+
+    #[test]
+    fn fork_dereferenced() {
+        setup();
+        let items = sample(100);
+        let mut t = Trie::new();
+        for (k, v) in &items {
+            t.insert(k, v).unwrap();
+        }
+        t.commit().unwrap();
+
+        // the parent still owns the nodes it shares with the fork
+        let mut fork = t.fork().unwrap();
+        fork.insert(&items[0].0, [0x42]).unwrap();
+        let inserted = {
+            let changes = fork.commit().unwrap();
+            assert!(changes.dereferenced.is_empty());
+            changes
+                .inserted
+                .iter()
+                .map(|(hash, _)| hash.to_vec())
+                .collect::<Vec<_>>()
+        };
+        t.insert(&items[0].0, [0x43]).unwrap();
+        assert!(!t.commit().unwrap().dereferenced.is_empty());
+
+        // the fork only dereferences the nodes it committed
+        fork.insert(&items[0].0, [0x44]).unwrap();
+        let changes = fork.commit().unwrap();
+        assert!(!changes.dereferenced.is_empty());
+        assert!(changes
+            .dereferenced
+            .iter()
+            .all(|hash| inserted.contains(hash)));
+    }
+
+    #[test]
+    fn fork_store() {
+        setup();
+        let mut items = sample(200);
+        let mut store = HashMap::new();
+        let root = {
+            let mut t = Trie::with_store(&mut store);
+            for (k, v) in &items {
+                t.insert(k, v).unwrap();
+            }
+            t.root().unwrap().to_vec()
+        };
+
+        // forks lazily load the nodes the parent didn't
+        let store = Arc::new(RwLock::new(store));
+        let mut t = Trie::from_root(&root, store.clone());
+        assert_eq!(t.get_loading(&items[0].0).unwrap(), Some(&*items[0].1));
+        let mut fork = t.fork().unwrap();
+        for (k, v) in &items {
+            assert_eq!(fork.get_loading(k).unwrap(), Some(&**v));
+        }
+        for (i, (k, v)) in items.iter_mut().enumerate() {
+            if i % 3 == 0 {
+                *v = vec![0x42; 1 + i % 50];
+                fork.insert(k, &v).unwrap();
+            }
+        }
+        let fork_root = fork.root().unwrap().to_vec();
+        assert_eq!(
+            fork_root,
+            &*trie_root::<KeccakHasher, _, _, _>(items.clone())
+        );
+
+        // forks of forks
+        let mut nested = fork.fork().unwrap();
+        let (k, _) = items.remove(7);
+        nested.remove(&k).unwrap();
+        assert_eq!(
+            nested.root().unwrap(),
+            &*trie_root::<KeccakHasher, _, _, _>(items.clone())
+        );
+        assert_eq!(fork.root().unwrap(), &*fork_root);
+        assert_eq!(t.root().unwrap(), &*root);
+
+        // the store is shared, not copied
+        let nested_root = nested.root().unwrap().to_vec();
+        let mut reopened = Trie::from_root(&nested_root, store.clone());
+        for (k, v) in &items {
+            assert_eq!(reopened.get_loading(k).unwrap(), Some(&**v));
+        }
+
+        // dropped forks don't write anything
+        let len = store.read().unwrap().len();
+        let mut dropped = t.fork().unwrap();
+        dropped.insert([0x42], [0x42]).unwrap();
+        drop(dropped);
+        assert_eq!(store.read().unwrap().len(), len);
+
+        // compacting copies the shared nodes
+        nested.compact().unwrap();
+        for (k, v) in &items {
+            assert_eq!(nested.get(k).unwrap(), Some(&**v));
+        }
+    }
 }